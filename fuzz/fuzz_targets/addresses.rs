@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use esp_01::fuzzing::{parse_ipv4, parse_mac};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_mac(data);
+    let _ = parse_ipv4(data);
+});
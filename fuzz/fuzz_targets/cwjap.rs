@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use esp_01::fuzzing::{parse_ap_link, parse_stored_ap_ssid};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_ap_link(data);
+    let _ = parse_stored_ap_ssid(data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use esp_01::fuzzing::parse_ap_info;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_ap_info(data);
+});
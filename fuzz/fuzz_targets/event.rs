@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use esp_01::fuzzing::parse_event;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_event(data);
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use esp_01::fuzzing::split_fields;
+
+fuzz_target!(|data: &[u8]| {
+    // The fields and the commas between them make up the whole line
+    let fields = split_fields(data).count();
+    let length: usize = split_fields(data).map(|field| field.len()).sum();
+    assert_eq!(length + fields - 1, data.len());
+});
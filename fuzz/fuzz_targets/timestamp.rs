@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use esp_01::Timestamp;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(time) = Timestamp::parse(data) {
+        assert!((1..=12).contains(&time.month) && (1..=31).contains(&time.day));
+        assert!(time.hour < 24 && time.minute < 60 && time.second <= 60);
+    }
+});
@@ -0,0 +1,415 @@
+use embedded_hal as hal;
+
+use core::fmt;
+use arrayvec::{Array, ArrayString, ArrayVec};
+
+use crate::{ConnectionType, Esp8266, Error, Incoming, LongTimer, Millisecond, needs_escape, serial};
+
+/**
+  Size of the buffer in `ConnectionWriter`. Each time it fills up, its content
+  is sent as one message
+*/
+const WRITER_BUFFER_SIZE: usize = 64;
+
+/// Longest keepalive interval accepted by the device, in seconds
+pub(crate) const MAX_KEEPALIVE: u16 = 7200;
+
+const HTTP_PORT: u16 = 80;
+const HTTPS_PORT: u16 = 443;
+
+/**
+  Where and how to connect when opening a connection
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ConnectionParams<'a> {
+    pub kind: ConnectionType,
+    /// Domain name or IP address of the remote end
+    pub host: &'a str,
+    pub port: u16,
+    /// Interval in seconds between TCP keepalive packets, 1 to 7200. Only
+    /// valid for TCP and SSL connections. If `None`, the default set with
+    /// `Esp8266::set_default_keepalive` is used
+    pub keepalive: Option<u16>,
+}
+
+impl<'a> ConnectionParams<'a> {
+    pub fn new(kind: ConnectionType, host: &'a str, port: u16) -> Self {
+        Self { kind, host, port, keepalive: None }
+    }
+
+    /**
+      A TCP connection to the default HTTP port of `host`
+    */
+    pub fn http(host: &'a str) -> Self {
+        Self::new(ConnectionType::Tcp, host, HTTP_PORT)
+    }
+
+    /**
+      An SSL connection to the default HTTPS port of `host`
+    */
+    pub fn https(host: &'a str) -> Self {
+        Self::new(ConnectionType::Ssl, host, HTTPS_PORT)
+    }
+
+    pub fn with_keepalive(self, seconds: u16) -> Self {
+        Self { keepalive: Some(seconds), ..self }
+    }
+
+    /**
+      Checks that the parameters can be sent to the device, returning
+      `Error::InvalidArgument` with the name of the invalid field otherwise
+    */
+    pub fn validate<R, T, P>(&self) -> Result<(), Error<R, T, P>> {
+        cipstart_host(self.host)?;
+        if self.port == 0 {
+            return Err(Error::InvalidArgument("port"));
+        }
+        match (self.kind, self.keepalive) {
+            (_, None) => Ok(()),
+            (ConnectionType::Tcp, Some(1..=MAX_KEEPALIVE))
+                | (ConnectionType::Ssl, Some(1..=MAX_KEEPALIVE)) => Ok(()),
+            _ => Err(Error::InvalidArgument("keepalive"))
+        }
+    }
+}
+
+/**
+  Checks that `host` can be sent to the device, returning it with the
+  brackets around an IPv6 address like `[fe80::1]` removed since the device
+  expects the bare address
+*/
+pub(crate) fn cipstart_host<R, T, P>(host: &str) -> Result<&str, Error<R, T, P>> {
+    if host.is_empty() || host.bytes().any(|byte| byte.is_ascii_control()) {
+        return Err(Error::InvalidArgument("host"));
+    }
+    match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        Some(address) if address.contains(':') => Ok(address),
+        _ => Ok(host)
+    }
+}
+
+/**
+  Appends `host` to `out` in the form it is sent to the device in
+  `AT+CIPSTART`: quoted, with quotes, commas and backslashes escaped so that
+  a host like `a","b` can't change the meaning of the command, and without
+  the brackets around an IPv6 address.
+
+  Returns `Error::InvalidArgument` for empty hosts and hosts containing
+  control characters, and `Error::Capacity` if the result doesn't fit in
+  `out`
+*/
+pub fn format_cipstart_host<A, R, T, P>(host: &str, out: &mut ArrayString<A>)
+    -> Result<(), Error<R, T, P>>
+where A: Array<Item = u8> + Copy
+{
+    let host = cipstart_host(host)?;
+    out.try_push('"')?;
+    for c in host.chars() {
+        if c.is_ascii() && needs_escape(c as u8) {
+            out.try_push('\\')?;
+        }
+        out.try_push(c)?;
+    }
+    out.try_push('"')?;
+    Ok(())
+}
+
+/**
+  Something which happened on an open `Connection`, returned by
+  `Connection::poll`
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConnectionEvent {
+    /// A message was received and stored in the buffer, contains the length
+    Data(usize),
+    /// The connection was closed by the remote end
+    Closed,
+}
+
+/**
+  An open connection which is closed when dropped.
+
+  Created by `Esp8266::open_connection`. Since errors can't be reported from
+  `drop`, `close` should be used if errors while closing need to be handled
+*/
+pub struct Connection<'a, Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    esp: &'a mut Esp8266<Tx, Rx, Timer, Rst, HwRst, Led>,
+    closed: bool,
+}
+
+impl<'a, Tx, Rx, Timer, Rst, HwRst, Led> Connection<'a, Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    pub(crate) fn new(esp: &'a mut Esp8266<Tx, Rx, Timer, Rst, HwRst, Led>) -> Self {
+        Self { esp, closed: false }
+    }
+
+    /**
+      Sends `data` over the connection
+    */
+    pub fn write(&mut self, data: &[u8]) -> return_type!(()) {
+        self.esp.transmit_parts(&[data])
+    }
+
+    /**
+      Waits for a message on the connection and copies it into `buffer`.
+      See `Esp8266::receive_data`
+    */
+    pub fn read(&mut self, buffer: &mut [u8]) -> return_type!(usize) {
+        let timeout = self.esp.state.timeouts.command;
+        self.esp.receive_data(buffer, timeout)
+    }
+
+    /**
+      Sends `frame` as one message without closing the connection. Fails with
+      `Error::ConnectionClosed` if the remote end has closed the connection.
+
+      Together with `read_frame` and `poll` this allows a long lived
+      connection to be used for many small messages, as for example MQTT does
+    */
+    pub fn write_frame(&mut self, frame: &[u8]) -> return_type!(()) {
+        if self.closed {
+            return Err(Error::ConnectionClosed);
+        }
+        self.write(frame)
+    }
+
+    /**
+      Waits up to `timeout` for a message and copies it into `buffer`,
+      returning its length. Fails with `Error::ConnectionClosed` if the remote
+      end has closed the connection
+    */
+    pub fn read_frame(&mut self, buffer: &mut [u8], timeout: Millisecond)
+        -> return_type!(usize)
+    {
+        match self.poll(buffer, timeout)? {
+            Some(ConnectionEvent::Data(length)) => Ok(length),
+            Some(ConnectionEvent::Closed) => Err(Error::ConnectionClosed),
+            None => Err(Error::RxError(serial::Error::TimedOut))
+        }
+    }
+
+    /**
+      Checks for incoming messages or the connection being closed, waiting at
+      most `timeout`. Returns `None` if nothing happened.
+
+      Received messages are copied into `buffer`, discarding what doesn't fit.
+      Once the connection has been closed, `Closed` is returned without
+      waiting
+    */
+    pub fn poll(&mut self, buffer: &mut [u8], timeout: Millisecond)
+        -> return_type!(Option<ConnectionEvent>)
+    {
+        if self.closed {
+            return Ok(Some(ConnectionEvent::Closed));
+        }
+        match self.esp.wait_for_incoming(timeout) {
+            Ok(Incoming::Data) => {
+                let length = self.esp.read_ipd_data(buffer, timeout)?;
+                Ok(Some(ConnectionEvent::Data(length)))
+            }
+            Ok(Incoming::Closed) => {
+                self.closed = true;
+                self.esp.connection_closed();
+                Ok(Some(ConnectionEvent::Closed))
+            }
+            Err(Error::RxError(serial::Error::TimedOut)) => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    /**
+      Returns false once the remote end is known to have closed the
+      connection
+    */
+    pub fn is_open(&self) -> bool {
+        !self.closed
+    }
+
+    /**
+      Returns a writer which allows formatted text to be sent over the
+      connection using `write!`
+    */
+    pub fn writer(&mut self) -> ConnectionWriter<'_, 'a, Tx, Rx, Timer, Rst, HwRst, Led> {
+        ConnectionWriter {
+            connection: self,
+            buffer: ArrayVec::new(),
+            result: Ok(()),
+        }
+    }
+
+    /**
+      Closes the connection, reporting any errors. Does nothing if the remote
+      end has already closed it
+    */
+    pub fn close(mut self) -> return_type!(()) {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        self.esp.close_connection()
+    }
+}
+
+impl<'a, Tx, Rx, Timer, Rst, HwRst, Led> Drop for Connection<'a, Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    fn drop(&mut self) {
+        if !self.closed {
+            // Best effort, errors can't be reported from here
+            self.esp.close_connection().ok();
+        }
+    }
+}
+
+/**
+  Buffered writer which implements `core::fmt::Write` for a `Connection`.
+
+  Written text is collected in a small buffer which is sent as a message
+  whenever it is full. The remaining text is sent by `flush`, or when the
+  writer is dropped. Since `fmt::Write` can't report the cause of errors,
+  errors from sending are returned by the next call to `flush`
+*/
+pub struct ConnectionWriter<'w, 'a, Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    connection: &'w mut Connection<'a, Tx, Rx, Timer, Rst, HwRst, Led>,
+    buffer: ArrayVec<[u8; WRITER_BUFFER_SIZE]>,
+    result: return_type!(()),
+}
+
+impl<'w, 'a, Tx, Rx, Timer, Rst, HwRst, Led> ConnectionWriter<'w, 'a, Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    /**
+      Sends the buffered text. Returns the first error that occurred while
+      sending since the last flush
+    */
+    pub fn flush(&mut self) -> return_type!(()) {
+        core::mem::replace(&mut self.result, Ok(()))?;
+        if !self.buffer.is_empty() {
+            let result = self.connection.write(&self.buffer);
+            self.buffer.clear();
+            result?;
+        }
+        Ok(())
+    }
+}
+
+impl<'w, 'a, Tx, Rx, Timer, Rst, HwRst, Led> fmt::Write for ConnectionWriter<'w, 'a, Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut remaining = s.as_bytes();
+        while !remaining.is_empty() {
+            let free = self.buffer.capacity() - self.buffer.len();
+            let (now, later) = remaining.split_at(free.min(remaining.len()));
+            self.buffer.extend(now.iter().cloned());
+            remaining = later;
+
+            if self.buffer.is_full() {
+                if let Err(e) = self.flush() {
+                    self.result = Err(e);
+                    return Err(fmt::Error);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'w, 'a, Tx, Rx, Timer, Rst, HwRst, Led> Drop for ConnectionWriter<'w, 'a, Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    fn drop(&mut self) {
+        // Best effort, errors can't be reported from here
+        self.flush().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{contains, started, TestError};
+
+    fn format(host: &str) -> Result<ArrayString<[u8; 32]>, TestError> {
+        let mut out = ArrayString::new();
+        format_cipstart_host(host, &mut out).map(|_| out)
+    }
+
+    #[test]
+    fn hosts_are_quoted() {
+        assert_eq!(format("example.com").unwrap().as_str(), "\"example.com\"");
+        assert_eq!(format("10.0.0.1").unwrap().as_str(), "\"10.0.0.1\"");
+    }
+
+    #[test]
+    fn quotes_and_commas_are_escaped() {
+        assert_eq!(format("a\",\"b").unwrap().as_str(), r#""a\"\,\"b""#);
+        assert_eq!(format("a\\b").unwrap().as_str(), r#""a\\b""#);
+    }
+
+    #[test]
+    fn ipv6_brackets_are_removed() {
+        assert_eq!(format("[::1]").unwrap().as_str(), "\"::1\"");
+        assert_eq!(format("[fe80::1]").unwrap().as_str(), "\"fe80::1\"");
+        // Only addresses lose their brackets
+        assert_eq!(format("[host]").unwrap().as_str(), "\"[host]\"");
+    }
+
+    #[test]
+    fn invalid_hosts_are_rejected() {
+        assert!(matches!(format(""), Err(Error::InvalidArgument("host"))));
+        assert!(matches!(format("bad\nhost"), Err(Error::InvalidArgument("host"))));
+        assert!(matches!(format("bad\rhost"), Err(Error::InvalidArgument("host"))));
+        assert!(matches!(format("a-host-name-which-does-not-fit.example.com"), Err(Error::Capacity(_))));
+    }
+
+    #[test]
+    fn the_formatted_host_is_what_is_sent() {
+        for &host in ["example.com", "a\",\"b", "[::1]"].iter() {
+            let mut esp = started(&[b"\r\nCONNECT\r\n\r\nOK\r\n"]);
+            esp.connect(&ConnectionParams::new(ConnectionType::Tcp, host, 80)).unwrap();
+            let formatted = format(host).unwrap();
+            assert!(contains(&esp.tx.written, formatted.as_bytes()), "{}", host);
+        }
+    }
+}
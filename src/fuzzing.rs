@@ -0,0 +1,24 @@
+//! Entry points for the fuzz targets in `fuzz/`. Only built with the
+//! `fuzzing` feature and not part of the public API
+
+use arrayvec::ArrayString;
+
+use crate::{ApInfo, Event, MAX_SSID_LENGTH};
+
+pub use crate::parse::{parse_ipv4, parse_mac, split_fields};
+
+pub fn parse_ap_info(line: &[u8]) -> Option<ApInfo> {
+    crate::parse_ap_info(line)
+}
+
+pub fn parse_ap_link(line: &[u8]) -> Option<(u8, i8)> {
+    crate::parse_ap_link(line)
+}
+
+pub fn parse_stored_ap_ssid(line: &[u8]) -> Option<ArrayString<[u8; MAX_SSID_LENGTH]>> {
+    crate::parse_stored_ap_ssid(line)
+}
+
+pub fn parse_event(line: &[u8]) -> Option<Event> {
+    crate::parse_event(line)
+}
@@ -0,0 +1,666 @@
+use embedded_hal as hal;
+
+use core::cmp::min;
+
+use arrayvec::{Array, ArrayString};
+
+use crate::{ConnectionParams, ConnectionType, Esp8266, Error, Incoming, LongTimer, serial};
+use crate::RECEIVE_CHUNK_SIZE;
+use crate::parse::{parse_u16, parse_u32};
+
+/**
+  Length of the longest `usize`, used when formatting `Content-Length`
+*/
+const CONTENT_LENGTH_STRING_LENGTH: usize = 20;
+
+/**
+  Port which is left out of the `Host` header
+*/
+const HTTP_PORT: u16 = 80;
+
+/**
+  Length of the longest port suffix of the `Host` header, `:65535`
+*/
+const PORT_SUFFIX_LENGTH: usize = 6;
+
+/**
+  Status and location of the body of a HTTP response received by `http_get`
+  or `http_post`. The response itself is stored in the buffer passed to them
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct HttpResponse {
+    /// The status code, for example 200
+    pub status: u16,
+    /// Offset of the first byte of the body in the response buffer
+    pub body_offset: usize,
+    /// Number of bytes of the response stored in the response buffer
+    pub length: usize,
+    /// The value of the `Content-Length` header if there was one
+    pub content_length: Option<usize>,
+    /// True if the body was sent with chunked transfer encoding. The body
+    /// stored in the response buffer has then already been decoded
+    pub chunked: bool,
+    /// Set if part of the response was received but didn't fit in the
+    /// response buffer
+    discarded: bool,
+}
+
+impl HttpResponse {
+    /**
+      Returns the part of `response`, the buffer the response was received in,
+      which contains the body
+    */
+    pub fn body<'a>(&self, response: &'a [u8]) -> &'a [u8] {
+        &response[self.body_offset..self.length]
+    }
+
+    /**
+      Returns true if the response did not fit in the response buffer, or
+      if less of the body than given by `Content-Length` was received
+    */
+    pub fn is_truncated(&self) -> bool {
+        self.discarded || match self.content_length {
+            Some(content_length) => self.length < self.body_offset + content_length,
+            None => false
+        }
+    }
+}
+
+/**
+  Parses the status line and headers at the start of `response`. Returns
+  `None` until the empty line ending the headers has been received
+*/
+pub fn parse_http_head(response: &[u8]) -> Option<HttpResponse> {
+    let head_length = response.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let mut lines = response[..head_length].split(|byte| *byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+
+    // Format: HTTP/1.1 <status> <reason>
+    let mut status_line = lines.next()?.split(|byte| *byte == b' ');
+    if !status_line.next()?.starts_with(b"HTTP/") {
+        return None;
+    }
+    let status = parse_u16(status_line.next()?)?;
+
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        if let Some(value) = header_value(line, b"content-length") {
+            content_length = parse_u32(value).map(|length| length as usize);
+        }
+        if let Some(value) = header_value(line, b"transfer-encoding") {
+            // Chunked is always the last of the listed encodings
+            chunked = value.len() >= 7
+                && value[value.len() - 7..].eq_ignore_ascii_case(b"chunked");
+        }
+    }
+
+    Some(HttpResponse {
+        status,
+        body_offset: head_length + 4,
+        length: response.len(),
+        content_length,
+        chunked,
+        discarded: false,
+    })
+}
+
+/**
+  Returns the value of the header `line` if its name is `name`, which must be
+  lower case
+*/
+pub fn header_value<'a>(line: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    let colon = line.iter().position(|byte| *byte == b':')?;
+    if !line[..colon].eq_ignore_ascii_case(name) {
+        return None;
+    }
+    let value = &line[colon + 1..];
+    let start = value.iter().position(|byte| *byte != b' ').unwrap_or(value.len());
+    Some(&value[start..])
+}
+
+/**
+  Appends `input` to `out` with every byte except letters, digits and
+  `-._~` percent encoded, for example to put a user provided value in the
+  query of the path passed to `http_get`.
+
+  Returns `Error::Capacity` if the result doesn't fit, in which case `out`
+  contains the part which fit without splitting an encoded byte
+*/
+pub fn percent_encode_into<A, R, T, P>(out: &mut ArrayString<A>, input: &str)
+    -> Result<(), Error<R, T, P>>
+where A: Array<Item = u8> + Copy
+{
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            out.try_push(char::from(byte))?;
+        }
+        else {
+            let encoded = [b'%', HEX[usize::from(byte >> 4)], HEX[usize::from(byte & 0xf)]];
+            // Can not fail since the bytes are ASCII
+            out.try_push_str(core::str::from_utf8(&encoded).unwrap())?;
+        }
+    }
+    Ok(())
+}
+
+/**
+  State of a `ChunkedDecoder` between two bytes
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ChunkState {
+    /// Reading the hexadecimal size of the next chunk
+    Size,
+    /// Skipping a chunk extension after the size
+    Extension,
+    /// Expecting the `\n` ending the size line
+    SizeEnd,
+    /// Reading the data of a chunk
+    Data,
+    /// Expecting the `\r` after the data of a chunk
+    DataCr,
+    /// Expecting the `\n` after the data of a chunk
+    DataLf,
+    /// At the start of a trailer line after the last chunk
+    Trailer,
+    /// Skipping a trailer line
+    TrailerLine,
+    /// Expecting the `\n` of the empty line ending the body
+    End,
+    Done,
+}
+
+/**
+  Decodes a body sent with `Transfer-Encoding: chunked`, removing the chunk
+  size lines and joining the data of the chunks.
+
+  The decoder keeps its state between calls so the body can be fed to it in
+  pieces, for example as `+IPD` messages arrive, regardless of where the
+  chunk boundaries fall
+*/
+#[derive(Debug, Clone)]
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    remaining: usize,
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self { state: ChunkState::Size, remaining: 0 }
+    }
+
+    /**
+      Returns true once the last, empty, chunk and the trailer after it have
+      been decoded. Any further input is ignored
+    */
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    /**
+      Decodes `input` into `output`, returning the number of bytes written or
+      `None` if the input is not valid chunked encoding. Decoded bytes which
+      don't fit in `output` are discarded
+    */
+    pub fn decode(&mut self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        self.decode_counting_overflow(input, output).map(|(written, _)| written)
+    }
+
+    /**
+      Like `decode` but also returns the number of decoded bytes which were
+      discarded because they didn't fit in `output`
+    */
+    fn decode_counting_overflow(&mut self, input: &[u8], output: &mut [u8])
+        -> Option<(usize, usize)>
+    {
+        let mut written = 0;
+        let mut overflow = 0;
+        for byte in input {
+            if self.step(*byte)? {
+                match output.get_mut(written) {
+                    Some(target) => {
+                        *target = *byte;
+                        written += 1;
+                    }
+                    None => overflow += 1
+                }
+            }
+        }
+        Some((written, overflow))
+    }
+
+    /**
+      Like `decode` but replaces the start of `buffer` with the decoded bytes,
+      which are never more than the encoded ones
+    */
+    pub fn decode_in_place(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        let mut written = 0;
+        for read in 0..buffer.len() {
+            let byte = buffer[read];
+            if self.step(byte)? {
+                buffer[written] = byte;
+                written += 1;
+            }
+        }
+        Some(written)
+    }
+
+    /**
+      Advances the state by one byte of input, returning whether the byte is
+      part of the decoded body or `None` if it is invalid
+    */
+    fn step(&mut self, byte: u8) -> Option<bool> {
+        self.state = match (self.state, byte) {
+            (ChunkState::Size, b';') => ChunkState::Extension,
+            (ChunkState::Size, b'\r') => ChunkState::SizeEnd,
+            (ChunkState::Size, _) => {
+                let digit = (byte as char).to_digit(16)? as usize;
+                self.remaining = self.remaining.checked_mul(16)?.checked_add(digit)?;
+                ChunkState::Size
+            }
+            (ChunkState::Extension, b'\r') => ChunkState::SizeEnd,
+            (ChunkState::Extension, _) => ChunkState::Extension,
+            (ChunkState::SizeEnd, b'\n') if self.remaining == 0 => ChunkState::Trailer,
+            (ChunkState::SizeEnd, b'\n') => ChunkState::Data,
+            (ChunkState::Data, _) => {
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    self.state = ChunkState::DataCr;
+                }
+                return Some(true);
+            }
+            (ChunkState::DataCr, b'\r') => ChunkState::DataLf,
+            (ChunkState::DataLf, b'\n') => ChunkState::Size,
+            (ChunkState::Trailer, b'\r') => ChunkState::End,
+            (ChunkState::Trailer, _) => ChunkState::TrailerLine,
+            (ChunkState::TrailerLine, b'\n') => ChunkState::Trailer,
+            (ChunkState::TrailerLine, _) => ChunkState::TrailerLine,
+            (ChunkState::End, b'\n') => ChunkState::Done,
+            (ChunkState::Done, _) => ChunkState::Done,
+            _ => return None
+        };
+        Some(false)
+    }
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+  Collects a HTTP response in a buffer from pieces of any size as they are
+  received, decoding chunked bodies on the way
+*/
+struct ResponseReader<'a> {
+    response: &'a mut [u8],
+    /// Number of bytes stored in `response`, which for chunked bodies is
+    /// less than the number of bytes received
+    stored: usize,
+    head: Option<HttpResponse>,
+    decoder: ChunkedDecoder,
+    /// Number of bytes of a body which isn't chunked received so far,
+    /// including the ones which were discarded
+    body_received: usize,
+    discarded: bool,
+}
+
+impl<'a> ResponseReader<'a> {
+    fn new(response: &'a mut [u8]) -> Self {
+        Self {
+            response,
+            stored: 0,
+            head: None,
+            decoder: ChunkedDecoder::new(),
+            body_received: 0,
+            discarded: false,
+        }
+    }
+
+    /**
+      Adds the next `piece` of the response. Returns `None` if the body is
+      not valid chunked encoding
+    */
+    fn feed(&mut self, mut piece: &[u8]) -> Option<()> {
+        let head = match self.head {
+            Some(head) => head,
+            None => {
+                let length = min(piece.len(), self.response.len() - self.stored);
+                self.response[self.stored..self.stored + length]
+                    .copy_from_slice(&piece[..length]);
+                self.stored += length;
+                piece = &piece[length..];
+
+                let head = match parse_http_head(&self.response[..self.stored]) {
+                    Some(head) => head,
+                    None => {
+                        self.discarded |= !piece.is_empty();
+                        return Some(());
+                    }
+                };
+                self.head = Some(head);
+                // The start of the body may have arrived along with the head
+                let body = &mut self.response[head.body_offset..self.stored];
+                if head.chunked {
+                    self.stored = head.body_offset + self.decoder.decode_in_place(body)?;
+                }
+                else {
+                    self.body_received = body.len();
+                }
+                head
+            }
+        };
+
+        let free = &mut self.response[self.stored..];
+        if head.chunked {
+            let (written, overflow) = self.decoder.decode_counting_overflow(piece, free)?;
+            self.stored += written;
+            self.discarded |= overflow > 0;
+        }
+        else {
+            let length = min(piece.len(), free.len());
+            free[..length].copy_from_slice(&piece[..length]);
+            self.stored += length;
+            self.body_received += piece.len();
+            self.discarded |= length < piece.len();
+        }
+        Some(())
+    }
+
+    /**
+      Returns true once the whole body has been received, which without
+      chunked encoding or `Content-Length` is only known once the server
+      closes the connection
+    */
+    fn is_complete(&self) -> bool {
+        match self.head {
+            Some(head) if head.chunked => self.decoder.is_done(),
+            Some(head) => head.content_length
+                .map(|length| self.body_received >= length)
+                .unwrap_or(false),
+            None => false
+        }
+    }
+
+    /**
+      Returns the head of the response, or `None` if it was never received
+      in full
+    */
+    fn finish(self) -> Option<HttpResponse> {
+        let mut head = self.head?;
+        head.length = self.stored;
+        head.discarded = self.discarded;
+        Some(head)
+    }
+}
+
+impl<Tx, Rx, Timer, Rst, HwRst, Led> Esp8266<Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    /**
+      Sends a HTTP GET request for `path` to `host`:`port` and receives the
+      response into `response`. `headers` are sent in addition to `Host` and
+      `Connection: close`.
+
+      The response is read until the server closes the connection or the
+      whole body given by `Content-Length` has been received. Parts of the
+      response which don't fit in `response` are discarded, which can be
+      checked with `HttpResponse::is_truncated`. Chunked responses are
+      decoded as they arrive and are complete once the last chunk has been
+      received
+    */
+    pub fn http_get(
+        &mut self,
+        host: &str,
+        port: u16,
+        path: &str,
+        headers: &[(&str, &str)],
+        response: &mut [u8]
+    ) -> return_type!(HttpResponse)
+    {
+        self.http_request("GET", host, port, path, headers, None, response)
+    }
+
+    /**
+      Like `http_get` but sends a POST request with `body`. The
+      `Content-Length` header is added automatically
+    */
+    pub fn http_post(
+        &mut self,
+        host: &str,
+        port: u16,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        response: &mut [u8]
+    ) -> return_type!(HttpResponse)
+    {
+        self.http_request("POST", host, port, path, headers, Some(body), response)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn http_request(
+        &mut self,
+        method: &str,
+        host: &str,
+        port: u16,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+        response: &mut [u8]
+    ) -> return_type!(HttpResponse)
+    {
+        if path.is_empty() {
+            return Err(Error::InvalidArgument("path"));
+        }
+        self.connect(&ConnectionParams::new(ConnectionType::Tcp, host, port))?;
+
+        let result = self.exchange_http(method, host, port, path, headers, body, response);
+        match result {
+            Ok((_, true)) => self.connection_closed(),
+            Ok((_, false)) => self.close_connection()?,
+            // Best effort, the original error is more interesting
+            Err(_) => { self.close_connection_if_open().ok(); }
+        }
+        result.map(|(head, _)| head)
+    }
+
+    /**
+      Sends the request and receives the response. Also returns whether the
+      server closed the connection
+    */
+    #[allow(clippy::too_many_arguments)]
+    fn exchange_http(
+        &mut self,
+        method: &str,
+        host: &str,
+        port: u16,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+        response: &mut [u8]
+    ) -> return_type!((HttpResponse, bool))
+    {
+        let mut content_length = ArrayString::<[u8; CONTENT_LENGTH_STRING_LENGTH]>::new();
+        if let Some(body) = body {
+            itoa::fmt(&mut content_length, body.len())?;
+        }
+        let content_length_header: &[&[u8]] = if body.is_some() {
+            &[b"Content-Length: ", content_length.as_bytes(), b"\r\n"]
+        }
+        else {
+            &[]
+        };
+
+        // The port is part of the host header unless it is the default one
+        let mut port_suffix = ArrayString::<[u8; PORT_SUFFIX_LENGTH]>::new();
+        if port != HTTP_PORT {
+            port_suffix.push(':');
+            itoa::fmt(&mut port_suffix, port)?;
+        }
+
+        let request_line: [&[u8]; 8] = [
+            method.as_bytes(), b" ", path.as_bytes(), b" HTTP/1.1\r\nHost: ",
+            host.as_bytes(), port_suffix.as_bytes(), b"\r\n", b"Connection: close\r\n"
+        ];
+        let header_lines = headers.iter().flat_map(|(name, value)| {
+            [name.as_bytes(), b": ", value.as_bytes(), b"\r\n"]
+        });
+        let request = request_line.iter().copied()
+            .chain(header_lines)
+            .chain(content_length_header.iter().copied())
+            .chain(core::iter::once(&b"\r\n"[..]))
+            .chain(body)
+            .flat_map(|part| part.iter().copied());
+        self.transmit_from(request)?;
+
+        let timeout = self.state.timeouts.command;
+        let mut reader = ResponseReader::new(response);
+        let mut closed = false;
+        // Every received byte has to pass through the decoder of a chunked
+        // body, so whole messages are read through a small buffer rather
+        // than straight into the response. The rest of the response is read
+        // even once the response buffer is full, otherwise it would be taken
+        // as the response to closing the connection
+        let mut chunk = [0; RECEIVE_CHUNK_SIZE];
+        while !reader.is_complete() {
+            if let Incoming::Closed = self.wait_for_incoming(timeout)? {
+                closed = true;
+                break;
+            }
+            let mut remaining = self.read_ipd_length(timeout)?;
+            while remaining > 0 {
+                let length = min(remaining, chunk.len());
+                for byte in chunk[..length].iter_mut() {
+                    *byte = self.read_byte(timeout)?;
+                }
+                reader.feed(&chunk[..length]).ok_or(Error::InvalidResponse)?;
+                remaining -= length;
+            }
+        }
+
+        let head = reader.finish().ok_or(Error::InvalidResponse)?;
+        Ok((head, closed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{contains, started};
+
+    const CHUNKED_HEAD: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    fn read(response: &mut [u8], pieces: &[&[u8]]) -> (HttpResponse, bool) {
+        let mut reader = ResponseReader::new(response);
+        for piece in pieces {
+            reader.feed(piece).unwrap();
+        }
+        let complete = reader.is_complete();
+        (reader.finish().unwrap(), complete)
+    }
+
+    #[test]
+    fn chunked_bodies_are_decoded_across_pieces() {
+        let mut response = [0; 64];
+        let (head, complete) = read(
+            &mut response,
+            &[CHUNKED_HEAD, b"5\r\nhel", b"lo\r\n6\r\n world\r\n0\r\n\r\n"]
+        );
+        assert!(complete);
+        assert!(!head.is_truncated());
+        assert_eq!(head.body(&response), b"hello world");
+    }
+
+    #[test]
+    fn chunked_bodies_which_do_not_fit_are_truncated() {
+        let mut response = [0; CHUNKED_HEAD.len() + 4];
+        // The first piece doesn't fit either, the decoder still has to see
+        // all of it to find the following chunks
+        let (head, complete) = read(
+            &mut response,
+            &[CHUNKED_HEAD, b"5\r\nhello\r\n6\r\n wo", b"rld\r\n0\r\n\r\n"]
+        );
+        assert!(complete);
+        assert!(head.is_truncated());
+        assert_eq!(head.body(&response), b"hell");
+
+        // Filling the buffer exactly is not truncation
+        let mut response = [0; CHUNKED_HEAD.len() + 5];
+        let (head, _) = read(&mut response, &[CHUNKED_HEAD, b"5\r\nhello\r\n0\r\n\r\n"]);
+        assert!(!head.is_truncated());
+        assert_eq!(head.body(&response), b"hello");
+    }
+
+    #[test]
+    fn chunks_arriving_with_the_head_are_decoded() {
+        let mut response = [0; 64];
+        let (head, complete) = read(&mut response, &[
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n",
+            b"0\r\n\r\n"
+        ]);
+        assert!(complete);
+        assert_eq!(head.body(&response), b"abc");
+    }
+
+    #[test]
+    fn bodies_with_a_content_length() {
+        let mut response = [0; 64];
+        let (head, complete) = read(
+            &mut response,
+            &[b"HTTP/1.1 404 Not Found\r\nContent-Length: 5\r\n\r\nhe", b"llo"]
+        );
+        assert!(complete);
+        assert_eq!(head.status, 404);
+        assert!(!head.is_truncated());
+        assert_eq!(head.body(&response), b"hello");
+    }
+
+    #[test]
+    fn invalid_chunks_are_rejected() {
+        let mut response = [0; 64];
+        let mut reader = ResponseReader::new(&mut response);
+        reader.feed(CHUNKED_HEAD).unwrap();
+        assert_eq!(reader.feed(b"x\r\n"), None);
+    }
+
+    #[test]
+    fn responses_which_do_not_fit_are_read_to_the_end() {
+        let mut esp = started(&[
+            b"\r\nCONNECT\r\n\r\nOK\r\n",
+            b"\r\nOK\r\n> ",
+            b"\r\nSEND OK\r\n",
+            b"\r\n+IPD,43:HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123",
+            b"\r\n+IPD,6:456789",
+            b"\r\nCLOSED\r\n\r\nOK\r\n",
+        ]);
+        let mut response = [0; 40];
+        let head = esp.http_get("example.com", 8080, "/", &[], &mut response).unwrap();
+        assert!(head.is_truncated());
+        assert_eq!(head.status, 200);
+        assert!(contains(&esp.tx.written, b"Host: example.com:8080\r\n"));
+        assert!(contains(&esp.tx.written, b"AT+CIPCLOSE\r\n"));
+    }
+
+    #[test]
+    fn the_default_port_is_left_out_of_the_host_header() {
+        let mut esp = started(&[
+            b"\r\nCONNECT\r\n\r\nOK\r\n",
+            b"\r\nOK\r\n> ",
+            b"\r\nSEND OK\r\n",
+            b"\r\n+IPD,27:HTTP/1.1 204 No Content\r\n\r\n",
+            b"\r\nCLOSED\r\n",
+        ]);
+        let mut response = [0; 64];
+        let head = esp.http_get("example.com", 80, "/", &[], &mut response).unwrap();
+        assert_eq!(head.status, 204);
+        assert!(contains(&esp.tx.written, b"Host: example.com\r\n"));
+    }
+}
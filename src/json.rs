@@ -0,0 +1,224 @@
+use core::fmt::Write;
+
+use embedded_hal as hal;
+
+use arrayvec::{ArrayString, ArrayVec};
+
+use crate::{Esp8266, Error, LongTimer, serial};
+
+/**
+  Length of the longest formatted number. The longest `f32` written without
+  an exponent, like `-0.000...1`, is 47 bytes
+*/
+const NUMBER_STRING_LENGTH: usize = 64;
+
+/**
+  A value in a JSON object sent by `Esp8266::transmit_json`
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JsonValue<'a> {
+    Int(i64),
+    Str(&'a str),
+    Bool(bool),
+    /// Sent as `null` if it is infinite or NaN since JSON can't represent that
+    Float(f32),
+}
+
+/**
+  A piece of the JSON object which is sent as a unit
+*/
+#[derive(Clone)]
+enum Piece<'a> {
+    Literal(&'static [u8]),
+    /// A string which is escaped as it is sent
+    Escaped(&'a [u8]),
+    Number(ArrayString<[u8; NUMBER_STRING_LENGTH]>),
+}
+
+impl<'a> Piece<'a> {
+    fn get(&self, offset: usize) -> Option<u8> {
+        match self {
+            Piece::Literal(bytes) | Piece::Escaped(bytes) => bytes.get(offset).cloned(),
+            Piece::Number(number) => number.as_bytes().get(offset).cloned(),
+        }
+    }
+}
+
+/**
+  Produces the bytes of a JSON object with the keys and values of `pairs`,
+  one pair at a time so the object never has to be stored in memory.
+
+  This is not a general JSON library, only flat objects with the values in
+  `JsonValue` are supported. Keys and strings are escaped as required by JSON
+*/
+#[derive(Clone)]
+pub struct JsonSender<'a, I> {
+    pairs: I,
+    /// The pieces of the current pair
+    pieces: ArrayVec<[Piece<'a>; 8]>,
+    piece: usize,
+    offset: usize,
+    /// The rest of the escape sequence of the last byte of a string
+    escape: ArrayVec<[u8; 6]>,
+    escape_offset: usize,
+    first: bool,
+    done: bool,
+}
+
+impl<'a, I> JsonSender<'a, I>
+where I: Iterator<Item = (&'a str, JsonValue<'a>)>
+{
+    pub fn new(pairs: I) -> Self {
+        Self {
+            pairs,
+            pieces: ArrayVec::new(),
+            piece: 0,
+            offset: 0,
+            escape: ArrayVec::new(),
+            escape_offset: 0,
+            first: true,
+            done: false,
+        }
+    }
+
+    /**
+      Prepares the pieces of the next pair, or the end of the object. Returns
+      false once the object is complete
+    */
+    fn next_pair(&mut self) -> bool {
+        if self.done {
+            return false;
+        }
+        self.pieces.clear();
+        self.piece = 0;
+        self.offset = 0;
+
+        let (key, value) = match self.pairs.next() {
+            Some(pair) => pair,
+            None => {
+                self.pieces.push(Piece::Literal(if self.first {b"{}"} else {b"}"}));
+                self.done = true;
+                return true;
+            }
+        };
+        self.pieces.push(Piece::Literal(if self.first {b"{\""} else {b",\""}));
+        self.first = false;
+        self.pieces.push(Piece::Escaped(key.as_bytes()));
+        self.pieces.push(Piece::Literal(b"\":"));
+        match value {
+            JsonValue::Int(value) => {
+                let mut number = ArrayString::new();
+                // An i64 is at most 20 bytes long
+                itoa::fmt(&mut number, value).unwrap();
+                self.pieces.push(Piece::Number(number));
+            }
+            JsonValue::Str(value) => {
+                self.pieces.push(Piece::Literal(b"\""));
+                self.pieces.push(Piece::Escaped(value.as_bytes()));
+                self.pieces.push(Piece::Literal(b"\""));
+            }
+            JsonValue::Bool(true) => self.pieces.push(Piece::Literal(b"true")),
+            JsonValue::Bool(false) => self.pieces.push(Piece::Literal(b"false")),
+            JsonValue::Float(value) if value.is_finite() => {
+                let mut number = ArrayString::new();
+                write!(number, "{}", value).unwrap();
+                self.pieces.push(Piece::Number(number));
+            }
+            JsonValue::Float(_) => self.pieces.push(Piece::Literal(b"null")),
+        }
+        true
+    }
+}
+
+impl<'a, I> Iterator for JsonSender<'a, I>
+where I: Iterator<Item = (&'a str, JsonValue<'a>)>
+{
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        loop {
+            if let Some(&byte) = self.escape.get(self.escape_offset) {
+                self.escape_offset += 1;
+                return Some(byte);
+            }
+            let (byte, escaped) = match self.pieces.get(self.piece) {
+                Some(piece) => (piece.get(self.offset), matches!(piece, Piece::Escaped(_))),
+                None => {
+                    if self.next_pair() {
+                        continue;
+                    }
+                    return None;
+                }
+            };
+            let byte = match byte {
+                Some(byte) => byte,
+                None => {
+                    self.piece += 1;
+                    self.offset = 0;
+                    continue;
+                }
+            };
+            self.offset += 1;
+            if escaped {
+                if let Some(sequence) = escape_sequence(byte) {
+                    self.escape = sequence;
+                    self.escape_offset = 0;
+                    continue;
+                }
+            }
+            return Some(byte);
+        }
+    }
+}
+
+/**
+  Returns the JSON escape sequence for `byte` if it has to be escaped in a
+  string
+*/
+fn escape_sequence(byte: u8) -> Option<ArrayVec<[u8; 6]>> {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let mut sequence = ArrayVec::new();
+    sequence.push(b'\\');
+    match byte {
+        b'"' | b'\\' => sequence.push(byte),
+        b'\n' => sequence.push(b'n'),
+        b'\r' => sequence.push(b'r'),
+        b'\t' => sequence.push(b't'),
+        0..=0x1f => {
+            sequence.extend(b"u00".iter().cloned());
+            sequence.push(HEX[usize::from(byte >> 4)]);
+            sequence.push(HEX[usize::from(byte & 0xf)]);
+        }
+        _ => return None
+    }
+    Some(sequence)
+}
+
+impl<Tx, Rx, Timer, Rst, HwRst, Led> Esp8266<Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    /**
+      Sends a JSON object with the keys and values of `pairs` as one message
+      over the currently open connection, for example
+
+      ```rust,ignore
+      esp.transmit_json([
+          ("temperature", JsonValue::Float(21.5)),
+          ("sensor", JsonValue::Str("kitchen")),
+      ].iter().cloned())?;
+      ```
+
+      Like `transmit_from`, `pairs` is run twice, once to compute the length
+      of the message
+    */
+    pub fn transmit_json<'a, I>(&mut self, pairs: I) -> return_type!(())
+    where I: Iterator<Item = (&'a str, JsonValue<'a>)> + Clone
+    {
+        self.transmit_from(JsonSender::new(pairs))
+    }
+}
@@ -4,15 +4,68 @@ use embedded_hal as hal;
 
 use nb::block;
 
-use core::cmp::min;
-use core::fmt::{self};
-use arrayvec::{CapacityError, ArrayString};
-use itoa;
+use core::cmp::{max, min};
+use core::convert::TryFrom;
+use core::fmt::{self, Write};
+use arrayvec::{Array, ArrayString, ArrayVec, CapacityError};
 
+// Declared before the modules to make them usable there
+macro_rules! return_type {
+    ($ok:ty) => {
+        Result<$ok, Error<serial::Error<Rx::Error>, Tx::Error, Rst::Error>>
+    }
+}
+
+macro_rules! nb_return_type {
+    ($ok:ty) => {
+        nb::Result<$ok, Error<serial::Error<Rx::Error>, Tx::Error, Rst::Error>>
+    }
+}
+
+macro_rules! transmission_return_type {
+    ($ok:ty) => {
+        Result<$ok, TransmissionError<serial::Error<Rx::Error>, Tx::Error, Rst::Error>>
+    }
+}
+
+mod connection;
+#[cfg(feature = "http")]
+mod http;
+mod json;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+mod observer;
+mod parse;
+mod pin;
 mod serial;
+mod sntp;
 mod timing;
+mod transparent;
+#[cfg(test)]
+mod testing;
 
-pub use timing::{LongTimer, Second, Millisecond};
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+
+pub use connection::{
+    format_cipstart_host, Connection, ConnectionEvent, ConnectionParams, ConnectionWriter
+};
+#[cfg(feature = "http")]
+pub use http::{percent_encode_into, ChunkedDecoder, HttpResponse};
+pub use json::{JsonSender, JsonValue};
+pub use observer::Observer;
+pub use pin::NoPin;
+pub use sntp::Timestamp;
+pub use timing::{LongTimer, Second, Millisecond, TimeoutConfig};
+pub use transparent::{ReconnectPolicy, TransferMode, TransparentEvent};
+
+pub use parse::{parse_ipd_header, IpdHeader, Ipv4Address, MacAddress};
+
+use connection::{cipstart_host, MAX_KEEPALIVE};
+use transparent::MAX_IDLE_TIMEOUT;
+use observer::{ErrorCodeScanner, ObservedRx};
+use parse::{parse_hex_u32, parse_i8, parse_ipv4, parse_mac, parse_reset_cause, parse_u16, parse_u32, parse_u8, split_fields, truncated_string, unquote};
 
 /**
     Maximum length of an AT response (Length of message + CRLF)
@@ -21,6 +74,50 @@ pub use timing::{LongTimer, Second, Millisecond};
 */
 const AT_RESPONSE_BUFFER_SIZE: usize = 13;
 
+/**
+    Maximum length of a line read from the device. Longer lines are truncated
+*/
+const LINE_BUFFER_SIZE: usize = 64;
+/// Length of the longest `u32`, 4294967295. Every number sent as part of a
+/// command is formatted into a buffer of this size by `send_number`
+const U32_STRING_LENGTH: usize = 10;
+/// Sent by the device once it has started
+const READY_BANNER: &[u8] = b"ready\r\n";
+
+/**
+    Maximum length of the header of a `+IPD` message between `+IPD,` and `:`
+*/
+const IPD_HEADER_BUFFER_SIZE: usize = 32;
+
+/**
+    Maximum length of a message sent with a single `CIPSEND` unless the
+    device reports something else
+*/
+const DEFAULT_MAX_SEND_SIZE: usize = 2048;
+
+/**
+    Time to wait for a response to `AT` at each baud rate tried by `autobaud`
+*/
+const BAUD_PROBE_TIMEOUT: Millisecond = Millisecond(200);
+
+/**
+    Number of times `AT` is sent at each baud rate tried by `autobaud`. The
+    first attempt after changing the baud rate is often garbled
+*/
+const BAUD_PROBE_ATTEMPTS: usize = 2;
+
+/**
+    Size of the chunks passed to the sink in `receive_to`
+*/
+const RECEIVE_CHUNK_SIZE: usize = 64;
+
+/// Maximum length of an SSID accepted by the device
+const MAX_SSID_LENGTH: usize = 32;
+/// Maximum length of a WPA passphrase
+const MAX_PASSWORD_LENGTH: usize = 63;
+/// Minimum length of a WPA passphrase. Open networks use an empty password
+const MIN_PASSWORD_LENGTH: usize = 8;
+
 /**
   Possible responses from an esp8266 AT command.
 
@@ -31,10 +128,36 @@ const AT_RESPONSE_BUFFER_SIZE: usize = 13;
 pub enum ATResponse {
     Ok,
     Error,
+    Fail,
     Busy,
     WiFiGotIp,
 }
 
+/**
+  Reason for a failed attempt at joining an access point, as reported by the
+  `+CWJAP:<code>` line which newer firmware sends before `FAIL`
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JoinFailReason {
+    Timeout,
+    WrongPassword,
+    ApNotFound,
+    ConnectFailed,
+    /// The device did not report a reason, or reported an unknown code
+    Unknown(Option<u8>),
+}
+impl JoinFailReason {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => JoinFailReason::Timeout,
+            2 => JoinFailReason::WrongPassword,
+            3 => JoinFailReason::ApNotFound,
+            4 => JoinFailReason::ConnectFailed,
+            other => JoinFailReason::Unknown(Some(other))
+        }
+    }
+}
+
 /**
   Error type for esp communication.
 
@@ -50,10 +173,72 @@ pub enum Error<R, T, P> {
     PinError(P),
     /// Invalid or unexpected data received from the device
     UnexpectedResponse(ATResponse),
+    /// Data which was not a known response was received before timing out.
+    /// Contains the last bytes received, oldest first, and how many of
+    /// them are valid
+    UnexpectedRaw([u8; AT_RESPONSE_BUFFER_SIZE], usize),
     /// Errors from the formating of messages
     Fmt(fmt::Error),
     /// Error indicating an ArrayString wasn't big enough
-    Capacity(CapacityError)
+    Capacity(CapacityError),
+    /// An argument was rejected before being sent to the device. Contains the
+    /// name of the argument
+    InvalidArgument(&'static str),
+    /// Joining an access point failed
+    JoinError { code: JoinFailReason },
+    /// The command requires the device to be in a different multiplexing mode
+    WrongMuxMode { required: MuxMode, actual: MuxMode },
+    /// The payload of a message could not be sent within the payload timeout.
+    /// The device has discarded the partial message
+    SendTimedOut,
+    /// The device did not respond at any of the baud rates tried by `autobaud`
+    BaudRateNotFound,
+    /// Nothing answered `AT` with `OK`, or nothing was received while
+    /// waiting for the device to start in `Esp8266::new_with_timeout`.
+    /// Usually because of a wiring mistake such as swapped TX and RX
+    DeviceNotFound,
+    /// Commands can't be sent in transparent mode since they would be sent
+    /// as data. `exit_transparent_mode` must be called first
+    InTransparentMode,
+    /// `Esp8266::transparent_write` or `poll_transparent` was called without
+    /// entering transparent mode with `enter_transparent_mode` first
+    NotInTransparentMode,
+    /// The device reported `CONNECT FAIL` when opening a connection. In
+    /// multiple connection mode, the id of the link is included
+    ConnectFailed { link_id: Option<u8> },
+    /// A message without any data was sent. The device rejects `CIPSEND`
+    /// with a length of 0 so empty messages, like empty UDP datagrams used
+    /// as heartbeats, can't be sent
+    EmptyPayload,
+    /// The device did not send its `ready` banner after being powered up,
+    /// so it probably didn't restart. See `Esp8266::set_verify_reset`
+    ResetFailed,
+    /// The device did not report `WIFI CONNECTED` while starting, so it
+    /// couldn't join the access point, for example because of a wrong
+    /// password or being out of range
+    NotAssociated,
+    /// The device joined the access point while starting but never reported
+    /// `WIFI GOT IP`, which usually means that DHCP doesn't work
+    NoIpAddress,
+    /// All links are in use, see `Esp8266::open_link`
+    NoFreeLink,
+    /// The connection was closed by the remote end
+    ConnectionClosed,
+    /// The device reported receiving a different number of bytes than was
+    /// sent, so some of the message was lost
+    SendCountMismatch { expected: usize, got: usize },
+    /// A response from the device could not be parsed
+    InvalidResponse,
+    /// The device sent its `ready` banner while a response was expected, so
+    /// it restarted, for example because of a brown out. Everything set up
+    /// since it was started is lost, so it should be initialized again with
+    /// `Esp8266::reinitialize` rather than retrying the command
+    ModuleRebooted,
+    /// `Esp8266::begin_send` was called before the previous message was
+    /// finished with `end_send`
+    SendInProgress,
+    /// `Esp8266::send_chunk` or `end_send` was called without `begin_send`
+    SendNotStarted,
 }
 impl<R,T, P> From<fmt::Error> for Error<R,T, P> {
     fn from(other: fmt::Error) -> Error<R,T, P> {
@@ -74,7 +259,9 @@ impl<R,T,ErrType,P> From<CapacityError<ErrType>> for Error<R,T,P> {
 pub enum TransmissionStep {
     Connect,
     Send,
-    Close
+    Close,
+    /// Putting the device in deep sleep, see `Esp8266::send_once_and_sleep`
+    Sleep,
 }
 /**
   Error indicating failure to transmit a message.
@@ -86,6 +273,16 @@ pub struct TransmissionError<R, T, P> {
 }
 
 impl<R, T, P> TransmissionError<R, T, P> {
+    /// The step of the transmission that failed
+    pub fn step(&self) -> &TransmissionStep {
+        &self.step
+    }
+
+    /// The error which caused the transmission to fail
+    pub fn cause(&self) -> &Error<R, T, P> {
+        &self.cause
+    }
+
     pub fn try_step<RetType>(step: TransmissionStep, cause: Result<RetType, Error<R, T, P>>) 
         -> Result<RetType, Self>
     {
@@ -99,53 +296,506 @@ impl<R, T, P> TransmissionError<R, T, P> {
 }
 
 
+/**
+  Whether the device allows a single connection or multiple connections
+  at once, set by `set_multiplexing`
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MuxMode {
+    Single,
+    Multiple,
+}
+
+/**
+  A station joining or leaving the soft access point of the device
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ApEvent {
+    StationConnected(MacAddress),
+    StationDisconnected(MacAddress),
+}
+
+/**
+  Something unsolicited reported by the device, returned by
+  `Esp8266::poll_event`. Link ids are only included in multiple connection
+  mode
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Event {
+    /// A message was received and its payload stored in the buffer,
+    /// contains the number of bytes stored
+    Data { link_id: Option<u8>, length: usize },
+    /// A connection was opened, for example by a client of the server
+    Connected(Option<u8>),
+    /// A connection was closed
+    Closed(Option<u8>),
+    /// A station joined or left the soft access point
+    Station(ApEvent),
+}
+
+/**
+  Data arriving from the device on an open connection
+*/
+enum Incoming {
+    /// The start of a `+IPD` message
+    Data,
+    /// The connection was closed
+    Closed,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ConnectionType {
     Tcp,
-    Udp
+    Udp,
+    /// TCP with TLS, handled by the device
+    Ssl,
 }
 impl ConnectionType {
     pub fn as_str(&self) -> &str {
         match *self {
             ConnectionType::Tcp => "TCP",
-            ConnectionType::Udp => "UDP"
+            ConnectionType::Udp => "UDP",
+            ConnectionType::Ssl => "SSL",
         }
     }
 }
 
+/**
+  A link in multiple connection mode handed out by `Esp8266::open_link`
+*/
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct LinkId(u8);
 
-macro_rules! return_type {
-    ($ok:ty) => {
-        Result<$ok, Error<serial::Error<Rx::Error>, Tx::Error, Rst::Error>>
+impl LinkId {
+    /// The id used by the device, 0 to 4
+    pub fn id(self) -> u8 {
+        self.0
     }
 }
 
-macro_rules! transmission_return_type {
-    ($ok:ty) => {
-        Result<$ok, TransmissionError<serial::Error<Rx::Error>, Tx::Error, Rst::Error>>
+/**
+  Sleep modes which the device can use between communication, set by
+  `set_sleep_mode`
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SleepMode {
+    Disabled,
+    Light,
+    Modem,
+}
+impl SleepMode {
+    fn command(&self) -> &str {
+        match *self {
+            SleepMode::Disabled => "+SLEEP=0",
+            SleepMode::Light => "+SLEEP=1",
+            SleepMode::Modem => "+SLEEP=2",
+        }
+    }
+}
+
+/**
+  Hardware flow control used by the serial port of the device, set by
+  `set_baud_rate`
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FlowControl {
+    Disabled,
+    /// The device pulls RTS high when it can't receive more data
+    Rts,
+    /// The device waits for CTS before sending
+    Cts,
+    RtsCts,
+}
+impl FlowControl {
+    fn code(self) -> u32 {
+        match self {
+            FlowControl::Disabled => 0,
+            FlowControl::Rts => 1,
+            FlowControl::Cts => 2,
+            FlowControl::RtsCts => 3,
+        }
+    }
+}
+
+/**
+  Reason the device last started, as reported by the `rst cause` in its boot
+  banner
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WakeReason {
+    PowerOn,
+    ExternalReset,
+    Watchdog,
+    /// The deep sleep timer ran out
+    DeepSleep,
+    /// The banner was missing or garbled, or reported an unknown cause
+    Unknown(Option<u8>),
+}
+impl WakeReason {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => WakeReason::PowerOn,
+            2 => WakeReason::ExternalReset,
+            4 => WakeReason::Watchdog,
+            5 => WakeReason::DeepSleep,
+            other => WakeReason::Unknown(Some(other))
+        }
+    }
+
+    /**
+      Classifies the boot log captured with `power_up_with_boot_log`
+    */
+    pub fn from_boot_log(boot_log: &[u8]) -> Self {
+        parse_reset_cause(boot_log)
+            .map(WakeReason::from_code)
+            .unwrap_or(WakeReason::Unknown(None))
+    }
+}
+
+
+
+/**
+  Country code and range of WiFi channels the device is allowed to use, set by
+  `set_country`
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct CountryConfig {
+    /// Two letter ISO 3166 country code
+    pub code: ArrayString<[u8; 2]>,
+    pub start_channel: u8,
+    pub channel_count: u8,
+}
+
+/**
+  Maximum length of the version strings in `FirmwareVersion`. Longer versions
+  are truncated
+*/
+const VERSION_STRING_LENGTH: usize = 32;
+
+/**
+  Number of bytes of the boot log kept by `wake_reason`. The reset cause is
+  part of the first line
+*/
+const BOOT_BANNER_LENGTH: usize = 64;
+
+/**
+  Versions of the firmware running on the device as reported by `AT+GMR`
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct FirmwareVersion {
+    /// Version of the AT command firmware
+    pub at: ArrayString<[u8; VERSION_STRING_LENGTH]>,
+    /// Version of the SDK the firmware was built with
+    pub sdk: ArrayString<[u8; VERSION_STRING_LENGTH]>,
+}
+
+/**
+  Hardware information reported by `Esp8266::chip_info`
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChipInfo {
+    /// The version of the firmware image, which usually names the chip and
+    /// flash size it was built for, for example `2.2.1(ESP8266_1MB)`
+    pub bin_version: Option<ArrayString<[u8; VERSION_STRING_LENGTH]>>,
+    /// Size of the flash in bytes. When the firmware doesn't state it, this
+    /// is the end of the last flash partition, which is a lower bound
+    pub flash_size: Option<u32>,
+}
+
+/**
+  Whether the device acts as a WiFi station, a soft access point or both
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WifiMode {
+    Station,
+    SoftAp,
+    StationAndSoftAp,
+}
+impl WifiMode {
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(WifiMode::Station),
+            2 => Some(WifiMode::SoftAp),
+            3 => Some(WifiMode::StationAndSoftAp),
+            _ => None
+        }
+    }
+}
+
+/**
+  Result of `Esp8266::self_test`
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct SelfTestReport {
+    /// Whether the device responded to `AT`
+    pub responsive: bool,
+    /// The firmware version, if it could be read
+    pub version: Option<FirmwareVersion>,
+    /// The current WiFi mode, if it could be read
+    pub mode: Option<WifiMode>,
+}
+
+/**
+  Security used by an access point, as reported by `AT+CWLAP`
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encryption {
+    Open,
+    Wep,
+    WpaPsk,
+    Wpa2Psk,
+    WpaWpa2Psk,
+    Wpa2Enterprise,
+    Wpa3Psk,
+    Wpa2Wpa3Psk,
+    /// A code added by newer firmware
+    Unknown(u8),
+}
+impl Encryption {
+    pub fn from_ecn(ecn: u8) -> Self {
+        match ecn {
+            0 => Encryption::Open,
+            1 => Encryption::Wep,
+            2 => Encryption::WpaPsk,
+            3 => Encryption::Wpa2Psk,
+            4 => Encryption::WpaWpa2Psk,
+            5 => Encryption::Wpa2Enterprise,
+            6 => Encryption::Wpa3Psk,
+            7 => Encryption::Wpa2Wpa3Psk,
+            other => Encryption::Unknown(other)
+        }
     }
 }
 
+/**
+  An access point found by `Esp8266::scan_networks`.
+
+  `A` is the storage of the SSID. The default fits the longest SSID, use
+  `Esp8266::scan_networks_sized` for a smaller or larger one
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct ApInfo<A = [u8; MAX_SSID_LENGTH]>
+where A: Array<Item = u8> + Copy
+{
+    pub encryption: Encryption,
+    pub ssid: ArrayString<A>,
+    /// Set if the SSID didn't fit in `ssid` and was truncated
+    pub ssid_truncated: bool,
+    /// Signal strength in dBm
+    pub rssi: i8,
+    pub mac: MacAddress,
+    pub channel: u8,
+}
+
+/**
+  The radio link to the access point, reported by `Esp8266::radio_info`
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RadioInfo {
+    pub channel: u8,
+    /// Signal strength in dBm
+    pub rssi: i8,
+    /// Whether 802.11n, which allows high throughput (HT) rates, is enabled.
+    /// `None` if the firmware can't report it
+    pub high_throughput: Option<bool>,
+}
+
+/**
+  Number of bytes sent to and received from the device since it was created
+  or `Esp8266::reset_stats` was called. The firmware doesn't report traffic
+  so these are counted by the driver, including commands and responses.
+
+  The counters wrap around instead of overflowing
+*/
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Stats {
+    pub bytes_sent: u32,
+    pub bytes_received: u32,
+}
+
+/**
+  The access point stored in the flash of the device, reported by
+  `Esp8266::stored_ap_config`
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct StoredAp {
+    pub ssid: ArrayString<[u8; MAX_SSID_LENGTH]>,
+    /// Whether the device joins the access point by itself when it starts.
+    /// `None` if the firmware can't report it
+    pub auto_connect: Option<bool>,
+}
+
+/**
+  Maximum length of lines read for responses with long lines, like `+CWLAP`
+  where newer firmware adds several fields after the channel
+*/
+const LONG_LINE_LENGTH: usize = 128;
+
+/// Highest WiFi channel in the 2.4 GHz band
+const MAX_WIFI_CHANNEL: u8 = 14;
+/// GPIO pins of the module which are free to use. GPIO1 and GPIO3 are the
+/// serial port
+const MODULE_GPIO_PINS: [u8; 2] = [0, 2];
+/// Most connections a server can accept at the same time
+const MAX_SERVER_CONNECTIONS: u8 = 5;
+/// Baud rates supported by the serial port of the device
+const BAUD_RATES: core::ops::RangeInclusive<u32> = 110..=4_608_000;
+/// Longest host name whose address is kept by `send_data_resolved`
+const MAX_HOST_LENGTH: usize = 64;
+/// Highest link id in multiple connection mode
+const MAX_LINK_ID: u8 = 4;
+
+/**
+  The configuration applied to the device since it was created, replayed by
+  `Esp8266::reinitialize`
+*/
+#[derive(Clone, Default)]
+struct InitConfig {
+    multiplexing: bool,
+    sleep_mode: Option<SleepMode>,
+    passive_receive: bool,
+    /// Set between `pause_receive` and `resume_receive`
+    receive_paused: bool,
+    /// The access point joined with `join_access_point`, if any
+    ssid: Option<ArrayString<[u8; MAX_SSID_LENGTH]>>,
+    password: ArrayString<[u8; MAX_PASSWORD_LENGTH + 1]>,
+}
 
 ////////////////////////////////////////////////////////////////////////////////
 ////////////////////////////////////////////////////////////////////////////////
 
-const STARTUP_TIMEOUT: Second = Second(10);
-const DEFAULT_TIMEOUT: Second = Second(5);
 
 
+/**
+  The state of the driver which doesn't depend on the pins, kept apart so that
+  `with_status_led` can move it as a whole
+*/
+struct State {
+    mux_mode: MuxMode,
+    timeouts: TimeoutConfig,
+    max_send_size: usize,
+    transparent: bool,
+    /// A notification which was received after data by `poll_transparent`,
+    /// returned by the next call
+    transparent_event: Option<TransparentEvent>,
+    /// Set when a connection is closed, cleared once the post close delay
+    /// has passed
+    post_close_delay_pending: bool,
+    auto_recover_after: Option<u8>,
+    consecutive_timeouts: u8,
+    recovering: bool,
+    observer: Option<&'static dyn Observer>,
+    dry_run: bool,
+    firmware_version: Option<FirmwareVersion>,
+    /// The end of a response which was partially received when waiting for
+    /// it timed out. Kept until the next command is sent so that waiting
+    /// again can still match it
+    response_buffer: [u8; AT_RESPONSE_BUFFER_SIZE],
+    response_received: usize,
+    /// The type of the connection opened by `connect`, if it is still open
+    connection_type: Option<ConnectionType>,
+    /// Value of `LongTimer::elapsed` when the current command was sent
+    command_sent_at: Option<Millisecond>,
+    last_command_latency: Option<Millisecond>,
+    init_config: InitConfig,
+    default_keepalive: Option<u16>,
+    command_prefix: &'static [u8],
+    command_terminator: &'static [u8],
+    stats: Stats,
+    /// The last host resolved by `send_data_resolved` and its address
+    resolved_host: Option<(ArrayString<[u8; MAX_HOST_LENGTH]>, Ipv4Address)>,
+    verify_reset: bool,
+    /// Bit `n` is set if link `n` was opened with `connect_link` and not
+    /// closed yet
+    links_in_use: u8,
+    max_total_attempts: Option<u8>,
+    /// Retries left in the current operation if it is limited
+    retries_left: Option<u8>,
+    in_operation: bool,
+    /// Whether the device echoed the last `ATE0`
+    echo_was_on: bool,
+    /// Length declared by `begin_send` for the message being sent
+    send_declared: Option<usize>,
+    /// Bytes sent with `send_chunk` since `begin_send`
+    send_sent: usize,
+    /// Length of the last message sent by `transmit_data_no_ack` if its
+    /// `SEND OK` may still be on its way
+    unacknowledged_send: Option<usize>,
+    /// Error code reported by the device for the last command
+    error_codes: ErrorCodeScanner,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            mux_mode: MuxMode::Single,
+            timeouts: TimeoutConfig::default(),
+            max_send_size: DEFAULT_MAX_SEND_SIZE,
+            transparent: false,
+            transparent_event: None,
+            post_close_delay_pending: false,
+            auto_recover_after: None,
+            consecutive_timeouts: 0,
+            recovering: false,
+            observer: None,
+            dry_run: false,
+            firmware_version: None,
+            response_buffer: [0; AT_RESPONSE_BUFFER_SIZE],
+            response_received: 0,
+            connection_type: None,
+            command_sent_at: None,
+            last_command_latency: None,
+            init_config: InitConfig::default(),
+            default_keepalive: None,
+            command_prefix: b"AT",
+            command_terminator: b"\r\n",
+            stats: Stats::default(),
+            resolved_host: None,
+            verify_reset: false,
+            links_in_use: 0,
+            max_total_attempts: None,
+            retries_left: None,
+            in_operation: false,
+            echo_was_on: false,
+            send_declared: None,
+            send_sent: 0,
+            unacknowledged_send: None,
+            error_codes: ErrorCodeScanner::default(),
+        }
+    }
+}
+
 /**
   Struct for interracting with an esp8266 wifi module over USART
+
+  `HwRst` is the type of the optional pin connected to the RST pin of the
+  device. It defaults to `NoPin` for boards where only CHIP_EN is connected.
+  Similarly, `Led` is the type of an optional status LED set with
+  `with_status_led`
 */
-pub struct Esp8266<Tx, Rx, Timer, Rst>
+pub struct Esp8266<
+    Tx,
+    Rx,
+    Timer,
+    Rst,
+    HwRst = NoPin<<Rst as hal::digital::v2::OutputPin>::Error>,
+    Led = NoPin<<Rst as hal::digital::v2::OutputPin>::Error>
+>
 where Tx: hal::serial::Write<u8>,
       Rx: hal::serial::Read<u8>,
       Timer: LongTimer,
-      Rst: hal::digital::v2::OutputPin
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
 {
     tx: Tx,
     rx: Rx,
     timer: Timer,
-    chip_enable_pin: Rst
+    /// `None` if CHIP_EN is not controlled by the host
+    chip_enable_pin: Option<Rst>,
+    reset_pin: Option<HwRst>,
+    status_led: Option<Led>,
+    state: State,
 }
 
 impl<Tx, Rx, Timer, Rst> Esp8266<Tx, Rx, Timer, Rst>
@@ -165,143 +815,2550 @@ where Tx: hal::serial::Write<u8>,
     pub fn new(tx: Tx, rx: Rx, timer: Timer, chip_enable_pin: Rst)
         -> return_type!(Self)
     {
-        let mut result = Self {tx, rx, timer, chip_enable_pin};
-
-        result.reset()?;
-
-        Ok(result)
+        Self::setup(tx, rx, timer, chip_enable_pin, None)
     }
 
-    pub fn send_data(
-        &mut self,
-        connection_type: ConnectionType,
-        address: &str,
-        port: u16,
-        data: &str
-    ) -> transmission_return_type!(())
-    {
-        // Send a start connection message
-        let tcp_start_result = self.start_tcp_connection(connection_type, address, port);
-        TransmissionError::try_step(TransmissionStep::Connect, tcp_start_result)?;
-
-        TransmissionError::try_step(TransmissionStep::Send, self.transmit_data(data))?;
-
-        TransmissionError::try_step(TransmissionStep::Close, self.close_connection())
-    }
+    /**
+      Like `new` but waits at most `startup` for the device to start instead
+      of the default startup timeout, which is also used for later resets.
 
-    pub fn close_connection(&mut self) -> return_type!(()) {
-        self.send_at_command("+CIPCLOSE")?;
-        self.wait_for_ok(DEFAULT_TIMEOUT.into())
+      If nothing at all is received from the device in that time,
+      `Error::DeviceNotFound` is returned since that means that no device is
+      connected, or that it isn't powered. Other errors mean that a device
+      was found but didn't start properly, for example `Error::NotAssociated`
+      if it couldn't join the access point
+    */
+    pub fn new_with_timeout(tx: Tx, rx: Rx, timer: Timer, chip_enable_pin: Rst, startup: Second)
+        -> return_type!(Self)
+    {
+        let mut result = Self::build(tx, rx, timer, Some(chip_enable_pin), None);
+        result.state.timeouts.startup = startup.into();
+        match result.reset() {
+            Ok(()) => Ok(result),
+            // These are how waiting for a device which never answers ends
+            Err(Error::RxError(serial::Error::TimedOut))
+                | Err(Error::NotAssociated)
+                | Err(Error::ResetFailed)
+                if result.state.stats.bytes_received == 0 => Err(Error::DeviceNotFound),
+            Err(e) => Err(e)
+        }
     }
 
     /**
-      Turns off the device by setting chip_enable to 0
+      Like `new` but instead of resetting the device, checks that a device is
+      already running and responding on the serial port. Useful when the
+      device should keep its state, or to catch wiring mistakes early.
+
+      The firmware version is read and kept, see `cached_firmware_version`,
+      and the connection mode is read from the device. Returns
+      `Error::DeviceNotFound` if nothing answers `AT`
     */
-    pub fn power_down(&mut self) -> return_type!(()) {
-        self.chip_enable_pin.set_low().map_err(Error::PinError)
+    pub fn attach_verified(tx: Tx, rx: Rx, timer: Timer, chip_enable_pin: Rst)
+        -> return_type!(Self)
+    {
+        let mut result = Self::build(tx, rx, timer, Some(chip_enable_pin), None);
+        result.verify()?;
+        Ok(result)
     }
 
     /**
-      Resets the device by setting chip_enable to 0 and then back to 1
+      Like `new` but for boards where CHIP_EN is not controlled by the host,
+      for example because it also powers other circuitry. The device is
+      treated as always on and is restarted with `AT+RST` instead of by
+      toggling CHIP_EN.
+
+      `Rst` only provides the error type of the pins then, `NoPin` can be
+      used for it
     */
-    pub fn reset(&mut self) -> return_type!(()) {
-        self.power_down()?;
-        self.timer.start(Millisecond(10));
-        block!(self.timer.wait()).unwrap();
-        self.power_up()
+    pub fn new_without_chip_enable(tx: Tx, rx: Rx, timer: Timer) -> return_type!(Self) {
+        let mut result = Self::build(tx, rx, timer, None, None);
+        result.reset()?;
+        Ok(result)
     }
+}
 
+impl<Tx, Rx, Timer, Rst, HwRst> Esp8266<Tx, Rx, Timer, Rst, HwRst>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
     /**
-      Turns the device back on by setting chip_enable to high
+      Like `new` but for boards where the RST pin of the device is connected
+      to `reset_pin` in addition to CHIP_EN being connected to
+      `chip_enable_pin`. Resets will pulse both pins.
     */
-    pub fn power_up(&mut self) -> return_type!(()) {
-        self.chip_enable_pin.set_high().map_err(Error::PinError)?;
+    pub fn new_with_reset_pin(
+        tx: Tx,
+        rx: Rx,
+        timer: Timer,
+        chip_enable_pin: Rst,
+        reset_pin: HwRst
+    ) -> return_type!(Self)
+    {
+        Self::setup(tx, rx, timer, chip_enable_pin, Some(reset_pin))
+    }
 
-        // The esp01 sends a bunch of garbage over the serial port before starting properly,
-        // therefore we need to retry this until we get valid data or time out
-        let mut error_count = 0;
-        loop {
-            match self.wait_for_got_ip(STARTUP_TIMEOUT.into()) {
-                Ok(()) => break,
-                e @ Err(Error::RxError(serial::Error::TimedOut)) => return e,
-                e => {
-                    if error_count < 255 {
-                        error_count += 1;
-                        continue
-                    }
-                    else {
-                        return e
-                    }
-                }
-            }
-        }
+    fn setup(
+        tx: Tx,
+        rx: Rx,
+        timer: Timer,
+        chip_enable_pin: Rst,
+        reset_pin: Option<HwRst>
+    ) -> return_type!(Self)
+    {
+        let mut result = Self::build(tx, rx, timer, Some(chip_enable_pin), reset_pin);
 
-        // Turn off echo on the device and wait for it to process that command
-        self.send_at_command("E0")?;
-        self.wait_for_ok(DEFAULT_TIMEOUT.into())?;
+        result.reset()?;
 
-        Ok(())
+        Ok(result)
     }
 
-    pub fn pull_some_current(&mut self) -> return_type!(()) {
-        self.chip_enable_pin.set_high().map_err(Error::PinError)?;
+    fn build(
+        tx: Tx,
+        rx: Rx,
+        timer: Timer,
+        chip_enable_pin: Option<Rst>,
+        reset_pin: Option<HwRst>
+    ) -> Self
+    {
+        Self {
+            tx,
+            rx,
+            timer,
+            chip_enable_pin,
+            reset_pin,
+            status_led: None,
+            state: State::new(),
+        }
+    }
+}
 
-        self.timer.start(Millisecond(500));
-        block!(self.timer.wait()).unwrap();
-        self.chip_enable_pin.set_low().map_err(Error::PinError)
+impl<Tx, Rx, Timer, Rst, HwRst, Led> Esp8266<Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    /**
+      Adds a status LED which is turned on while a connection is open or data
+      is being sent and turned off when idle or after an error. Errors from
+      setting the LED are ignored
+    */
+    pub fn with_status_led<NewLed>(self, mut led: NewLed)
+        -> Esp8266<Tx, Rx, Timer, Rst, HwRst, NewLed>
+    where NewLed: hal::digital::v2::OutputPin<Error = Rst::Error>
+    {
+        led.set_low().ok();
+        Esp8266 {
+            tx: self.tx,
+            rx: self.rx,
+            timer: self.timer,
+            chip_enable_pin: self.chip_enable_pin,
+            reset_pin: self.reset_pin,
+            status_led: Some(led),
+            state: self.state,
+        }
     }
 
-    fn transmit_data(&mut self, data: &str) -> return_type!(()) {
-        self.start_transmission(data.len())?;
-        self.wait_for_prompt(DEFAULT_TIMEOUT.into())?;
-        self.send_raw(data.as_bytes())?;
-        self.wait_for_ok(DEFAULT_TIMEOUT.into())
+    /**
+      Sets the timeouts used when communicating with the device
+    */
+    pub fn set_timeouts(&mut self, timeouts: TimeoutConfig) {
+        self.state.timeouts = timeouts;
     }
 
-    fn start_tcp_connection (
-        &mut self,
-        connection_type: ConnectionType,
-        address: &str,
-        port: u16
-    ) -> return_type!(())
-    {
-        // Length of biggest u16:
-        const PORT_STRING_LENGTH: usize = 5;
-        let mut port_str = ArrayString::<[_;PORT_STRING_LENGTH]>::new();
-        // write!(&mut port_str, "{}", port)?;
-        itoa::fmt(&mut port_str, port)?;
-
-        self.send_raw("AT+CIPSTART=\"".as_bytes())?;
-        self.send_raw(connection_type.as_str().as_bytes())?;
-        self.send_raw("\",\"".as_bytes())?;
-        self.send_raw(address.as_bytes())?;
-        self.send_raw("\",".as_bytes())?;
-        self.send_raw(port_str.as_bytes())?;
-        self.send_raw("\r\n".as_bytes())?;
-        self.wait_for_ok(DEFAULT_TIMEOUT.into())
-    }
-
-    fn start_transmission(&mut self, message_length: usize) -> return_type!(()) {
-        // You can only send 2048 bytes per packet 
-        assert!(message_length < 2048);
-        let mut length_buffer = ArrayString::<[_; 4]>::new();
-        // write!(&mut length_buffer, "{}", message_length)?;
-        itoa::fmt(&mut length_buffer, message_length)?;
-
-        self.send_raw(b"AT+CIPSEND=")?;
-        self.send_raw(length_buffer.as_bytes())?;
-        self.send_raw(b"\r\n")?;
-        Ok(())
+    pub fn timeouts(&self) -> &TimeoutConfig {
+        &self.state.timeouts
     }
 
     /**
-      Sends the "AT${command}" to the device
+      Makes `reset` and `power_up` check that the device sends its `ready`
+      banner while starting, returning `Error::ResetFailed` if it doesn't.
+      This catches boards where toggling CHIP_EN doesn't actually restart
+      the device, which otherwise leads to confusing errors later on
     */
-    fn send_at_command(&mut self, command: &str) -> return_type!(()) {
-        self.send_raw(b"AT")?;
-        self.send_raw(command.as_bytes())?;
-        self.send_raw(b"\r\n")?;
-        Ok(())
+    pub fn set_verify_reset(&mut self, enabled: bool) {
+        self.state.verify_reset = enabled;
+    }
+
+    /**
+      Makes the device explain `ERROR` responses with a preceding
+      `ERR CODE:0x<code>` line using `AT+SYSLOG`, which is only supported by
+      newer firmware. The code is read with `take_error_code`
+    */
+    pub fn set_error_codes(&mut self, enabled: bool) -> return_type!(()) {
+        self.send_at_command(if enabled {"+SYSLOG=1"} else {"+SYSLOG=0"})?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Returns and clears the error code the device reported for the last
+      command, if any. Useful after a command fails with
+      `ATResponse::Error` to find out why, see `set_error_codes`
+    */
+    pub fn take_error_code(&mut self) -> Option<u32> {
+        self.state.error_codes.code.take()
+    }
+
+    /**
+      Sets the prefix and terminator of the commands sent to the device,
+      `AT` and `\r\n` by default. Only needed for firmware which deviates
+      from the standard AT command format. Responses are still expected to
+      be terminated by `\r\n`
+    */
+    pub fn set_command_format(&mut self, prefix: &'static [u8], terminator: &'static [u8]) {
+        self.state.command_prefix = prefix;
+        self.state.command_terminator = terminator;
+    }
+
+    /**
+      Sets the keepalive interval, 1 to 7200 seconds, used for TCP and SSL
+      connections whose `ConnectionParams` don't specify one. Useful when one
+      long lived connection is reused. `None`, the default, leaves keepalive
+      to the device
+    */
+    pub fn set_default_keepalive(&mut self, seconds: Option<u16>) -> return_type!(()) {
+        match seconds {
+            None | Some(1..=MAX_KEEPALIVE) => {
+                self.state.default_keepalive = seconds;
+                Ok(())
+            }
+            _ => Err(Error::InvalidArgument("seconds"))
+        }
+    }
+
+    /**
+      Sets an `Observer` which is told about every byte sent to and received
+      from the device. `None` removes it
+    */
+    pub fn set_observer(&mut self, observer: Option<&'static dyn Observer>) {
+        self.state.observer = observer;
+    }
+
+    /**
+      Enables or disables dry run mode. In dry run mode nothing is sent to the
+      device and every command succeeds without waiting for a response, which
+      allows checking the exact commands sent by higher level functions
+      without hardware. The commands can be recorded with an `Observer`,
+      which still sees everything that would have been sent.
+
+      Queries return `Error::InvalidResponse` since there is no data to parse
+      and receiving data times out immediately
+    */
+    pub fn set_dry_run(&mut self, enabled: bool) {
+        self.state.dry_run = enabled;
+    }
+
+    /**
+      Enables automatic recovery of a device which has stopped responding.
+
+      After `after_timeouts` consecutive commands have timed out waiting for a
+      response, the device is reset before the next command is sent. Any
+      received response resets the count. If the reset itself fails, the error
+      is returned from that command and another `after_timeouts` timeouts are
+      required before the next attempt, so a dead device isn't reset over and
+      over.
+
+      The device is brought back up with `reinitialize`, so the configuration
+      is restored but open connections and servers are lost. `None` disables
+      recovery, which is the default
+    */
+    pub fn set_auto_recover(&mut self, after_timeouts: Option<u8>) -> return_type!(()) {
+        if after_timeouts == Some(0) {
+            return Err(Error::InvalidArgument("after_timeouts"));
+        }
+        self.state.auto_recover_after = after_timeouts;
+        self.state.consecutive_timeouts = 0;
+        Ok(())
+    }
+
+    /**
+      Limits the number of attempts, including the first one, made within
+      one call to `send_to`, the `send_data` methods built on it or
+      `send_data_resolved`. Every retry counts towards the limit, whether
+      it is a reset by auto recovery or looking up a host again. Once the
+      limit is reached, the error which would have caused the retry is
+      returned.
+
+      This puts an upper bound on how long sending can take. `None`, the
+      default, doesn't limit the attempts
+    */
+    pub fn set_max_total_attempts(&mut self, attempts: Option<u8>) -> return_type!(()) {
+        if attempts == Some(0) {
+            return Err(Error::InvalidArgument("attempts"));
+        }
+        self.state.max_total_attempts = attempts;
+        Ok(())
+    }
+
+    /**
+      Starts an operation limited by `set_max_total_attempts`. Returns false
+      if already within one, in which case that one keeps its limit
+    */
+    fn begin_operation(&mut self) -> bool {
+        if self.state.in_operation {
+            return false;
+        }
+        self.state.in_operation = true;
+        self.state.retries_left = self.state.max_total_attempts.map(|attempts| attempts - 1);
+        true
+    }
+
+    fn end_operation(&mut self, outermost: bool) {
+        if outermost {
+            self.state.in_operation = false;
+            self.state.retries_left = None;
+        }
+    }
+
+    /**
+      Uses up one retry of the current operation. Returns false if no retries
+      are left
+    */
+    fn take_retry(&mut self) -> bool {
+        match self.state.retries_left.as_mut() {
+            None => true,
+            Some(0) => false,
+            Some(left) => {
+                *left -= 1;
+                true
+            }
+        }
+    }
+
+
+    pub fn send_data(
+        &mut self,
+        connection_type: ConnectionType,
+        address: &str,
+        port: u16,
+        data: &str
+    ) -> transmission_return_type!(())
+    {
+        self.send_data_bytes(connection_type, address, port, data.as_bytes())
+    }
+
+    /**
+      Like `send_data` but with the port given as a string, for example when
+      it comes from a configuration file. `port` must be a decimal number
+      between 1 and 65535, otherwise `Error::InvalidArgument` is returned
+      from the connect step
+    */
+    pub fn send_data_str_port(
+        &mut self,
+        connection_type: ConnectionType,
+        address: &str,
+        port: &str,
+        data: &str
+    ) -> transmission_return_type!(())
+    {
+        let port = parse_u16(port.as_bytes()).ok_or(Error::InvalidArgument("port"));
+        let port = TransmissionError::try_step(TransmissionStep::Connect, port)?;
+        self.send_data(connection_type, address, port, data)
+    }
+
+    /**
+      Like `send_data` but for arbitrary binary data.
+
+      `data` is sent verbatim: nothing is escaped and NUL or other non-text
+      bytes do not end the message. The length given to `CIPSEND` is always
+      exactly the number of bytes sent, which is `data.len()` unless the message
+      is split because it is longer than `max_send_size`
+    */
+    pub fn send_data_bytes(
+        &mut self,
+        connection_type: ConnectionType,
+        address: &str,
+        port: u16,
+        data: &[u8]
+    ) -> transmission_return_type!(())
+    {
+        self.send_data_parts(connection_type, address, port, &[data])
+    }
+
+    /**
+      Like `send_data` but the message is made up of several `parts` which
+      are sent back to back as a single message.
+
+      This avoids having to concatenate for example a HTTP header and body
+      into an intermediate buffer before sending
+    */
+    pub fn send_data_parts(
+        &mut self,
+        connection_type: ConnectionType,
+        address: &str,
+        port: u16,
+        parts: &[&[u8]]
+    ) -> transmission_return_type!(())
+    {
+        self.send_to(&ConnectionParams::new(connection_type, address, port), parts)
+    }
+
+    /**
+      Like `send_data_bytes` but looks up the address of `host` with
+      `resolve` once and connects to the address directly on later calls
+      with the same host. If connecting to the kept address fails, the host
+      is looked up again before retrying once. Useful for sending
+      periodically to the same host.
+
+      Since the connection is made to the address, this is not suitable for
+      SSL connections which need the host name
+    */
+    pub fn send_data_resolved(
+        &mut self,
+        connection_type: ConnectionType,
+        host: &str,
+        port: u16,
+        data: &[u8]
+    ) -> transmission_return_type!(())
+    {
+        let outermost = self.begin_operation();
+        let result = self.send_data_resolved_steps(connection_type, host, port, data);
+        self.end_operation(outermost);
+        result
+    }
+
+    fn send_data_resolved_steps(
+        &mut self,
+        connection_type: ConnectionType,
+        host: &str,
+        port: u16,
+        data: &[u8]
+    ) -> transmission_return_type!(())
+    {
+        let cached = match &self.state.resolved_host {
+            Some((name, address)) if name.as_str() == host => Some(*address),
+            _ => None
+        };
+        let address = match cached {
+            Some(address) => address,
+            None => {
+                let resolved = self.resolve_and_keep(host);
+                TransmissionError::try_step(TransmissionStep::Connect, resolved)?
+            }
+        };
+
+        match self.send_data_to_address(connection_type, address, port, data) {
+            Err(e) if cached.is_some()
+                && matches!(e.step(), TransmissionStep::Connect)
+                && self.take_retry() =>
+            {
+                // The address of the host may have changed
+                self.state.resolved_host = None;
+                let resolved = self.resolve_and_keep(host);
+                let address = TransmissionError::try_step(TransmissionStep::Connect, resolved)?;
+                self.send_data_to_address(connection_type, address, port, data)
+            }
+            result => result
+        }
+    }
+
+    fn send_data_to_address(
+        &mut self,
+        connection_type: ConnectionType,
+        address: Ipv4Address,
+        port: u16,
+        data: &[u8]
+    ) -> transmission_return_type!(())
+    {
+        // Length of the longest dotted quad
+        let mut host = ArrayString::<[u8; 15]>::new();
+        write!(host, "{}", address).unwrap();
+        self.send_to(&ConnectionParams::new(connection_type, &host, port), &[data])
+    }
+
+    fn resolve_and_keep(&mut self, host: &str) -> return_type!(Ipv4Address) {
+        let address = self.resolve(host)?;
+        // Hosts which are too long are looked up every time
+        self.state.resolved_host = ArrayString::from(host).ok().map(|name| (name, address));
+        Ok(address)
+    }
+
+    /**
+      Looks up the IPv4 address of `host` using the DNS server of the access
+      point, with `AT+CIPDOMAIN`
+    */
+    pub fn resolve(&mut self, host: &str) -> return_type!(Ipv4Address) {
+        if host.is_empty() {
+            return Err(Error::InvalidArgument("host"));
+        }
+        self.begin_command(b"+CIPDOMAIN=\"")?;
+        self.send_escaped(host.as_bytes())?;
+        self.send_raw(b"\"")?;
+        self.end_command()?;
+
+        // Format: +CIPDOMAIN:<ip>, where newer firmware quotes the address.
+        // DNS Fail is sent before ERROR if the lookup failed
+        let mut buffer = [0; LINE_BUFFER_SIZE];
+        let mut address = None;
+        loop {
+            match self.read_line(&mut buffer, self.state.timeouts.command)? {
+                b"OK" => return address.ok_or(Error::InvalidResponse),
+                b"ERROR" => return Err(Error::UnexpectedResponse(ATResponse::Error)),
+                line => {
+                    if let Some(value) = line.strip_prefix(b"+CIPDOMAIN:") {
+                        address = Some(parse_ipv4(value).ok_or(Error::InvalidResponse)?);
+                    }
+                }
+            }
+        }
+    }
+
+    /**
+      Reads the IP address of the device on the network of the access
+      point with `AT+CIPSTA?`. Returns `None` if it doesn't have one yet
+    */
+    pub fn station_ip(&mut self) -> return_type!(Option<Ipv4Address>) {
+        // Format: ip:<ip>, followed by lines for the gateway and netmask.
+        // Older firmware sends only the address, without the `ip:` label.
+        // Like everywhere else, addresses may or may not be quoted
+        let mut address = None;
+        self.query("+CIPSTA?", b"+CIPSTA:", |line| {
+            if line.starts_with(b"gateway:") || line.starts_with(b"netmask:") {
+                return Ok(());
+            }
+            let value = line.strip_prefix(b"ip:").unwrap_or(line);
+            address = Some(parse_ipv4(value).ok_or(Error::InvalidResponse)?);
+            Ok(())
+        })?;
+        Ok(address.filter(|address| !address.is_unspecified()))
+    }
+
+    /**
+      Opens a connection described by `params`, sends `parts` as a single
+      message and closes the connection again
+    */
+    pub fn send_to(&mut self, params: &ConnectionParams, parts: &[&[u8]])
+        -> transmission_return_type!(())
+    {
+        let outermost = self.begin_operation();
+        let result = self.send_to_steps(params, parts);
+        self.end_operation(outermost);
+        self.set_status_led(false);
+        result
+    }
+
+    fn send_to_steps(&mut self, params: &ConnectionParams, parts: &[&[u8]])
+        -> transmission_return_type!(())
+    {
+        // Send a start connection message
+        let tcp_start_result = self.connect(params);
+        TransmissionError::try_step(TransmissionStep::Connect, tcp_start_result)?;
+
+        TransmissionError::try_step(TransmissionStep::Send, self.transmit_parts(parts))?;
+
+        TransmissionError::try_step(TransmissionStep::Close, self.close_connection())
+    }
+
+    /**
+      Sends `data` as a single message over a new connection described by
+      `params` and then puts the device in deep sleep for `sleep`, the usual
+      cycle of a battery powered sensor. The device has to be started and
+      joined to the access point already, which it does by itself when it
+      has one stored.
+
+      The connection is not closed since deep sleep ends it anyway, which
+      saves waiting for `CLOSED` and the post close delay. Over UDP the
+      message is considered sent after the `udp_ack` timeout at the latest.
+      The device is put to sleep even if sending fails so that a failed
+      attempt doesn't drain the battery, and the first error is returned
+    */
+    pub fn send_once_and_sleep(&mut self, params: &ConnectionParams, data: &[u8], sleep: Millisecond)
+        -> transmission_return_type!(())
+    {
+        let outermost = self.begin_operation();
+        let sent = self.connect(params)
+            .map_err(|cause| TransmissionError { step: TransmissionStep::Connect, cause })
+            .and_then(|_| {
+                self.transmit_parts(&[data])
+                    .map_err(|cause| TransmissionError { step: TransmissionStep::Send, cause })
+            });
+        self.end_operation(outermost);
+        self.set_status_led(false);
+        self.state.connection_type = None;
+        let slept = TransmissionError::try_step(TransmissionStep::Sleep, self.deep_sleep(sleep));
+        sent.and(slept)
+    }
+
+    /**
+      Opens a connection to `address`:`port` which is closed when the returned
+      `Connection` is dropped or closed explicitly
+    */
+    pub fn open_connection(
+        &mut self,
+        connection_type: ConnectionType,
+        address: &str,
+        port: u16
+    ) -> return_type!(Connection<'_, Tx, Rx, Timer, Rst, HwRst, Led>)
+    {
+        self.open(&ConnectionParams::new(connection_type, address, port))
+    }
+
+    /**
+      Opens the connection described by `params` which is closed when the
+      returned `Connection` is dropped or closed explicitly
+    */
+    pub fn open(&mut self, params: &ConnectionParams)
+        -> return_type!(Connection<'_, Tx, Rx, Timer, Rst, HwRst, Led>)
+    {
+        self.connect(params)?;
+        Ok(Connection::new(self))
+    }
+
+    pub fn close_connection(&mut self) -> return_type!(()) {
+        self.connection_closed();
+        self.send_at_command("+CIPCLOSE")?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Closes the current connection if there is one.
+
+      Unlike `close_connection`, an `ERROR` reply (which the device sends
+      when there is no open connection, sometimes preceded by
+      `link is not valid`) is treated as success since there was nothing
+      to close. Useful for getting back to a clean state before retrying.
+    */
+    pub fn close_connection_if_open(&mut self) -> return_type!(()) {
+        match self.close_connection() {
+            Err(Error::UnexpectedResponse(ATResponse::Error)) => Ok(()),
+            other => other
+        }
+    }
+
+    /**
+      Connects to the access point `ssid` using `password`, which should be
+      empty for open networks.
+
+      The lengths of `ssid` and `password` are checked before anything is sent
+      to the device, returning `Error::InvalidArgument` with the name of the
+      offending argument if they are out of range.
+
+      If the device rejects the attempt, `Error::JoinError` is returned with
+      the reason reported by the device.
+    */
+    pub fn join_access_point(&mut self, ssid: &str, password: &str) -> return_type!(()) {
+        if ssid.is_empty() || ssid.len() > MAX_SSID_LENGTH {
+            return Err(Error::InvalidArgument("ssid"));
+        }
+        if password.len() > MAX_PASSWORD_LENGTH
+            || (!password.is_empty() && password.len() < MIN_PASSWORD_LENGTH)
+        {
+            return Err(Error::InvalidArgument("password"));
+        }
+
+        self.begin_command(b"+CWJAP=\"")?;
+        self.send_escaped(ssid.as_bytes())?;
+        self.send_raw(b"\",\"")?;
+        self.send_escaped(password.as_bytes())?;
+        self.send_raw(b"\"")?;
+        self.end_command()?;
+
+        let mut buffer = [0; LINE_BUFFER_SIZE];
+        let mut reason = JoinFailReason::Unknown(None);
+        loop {
+            match self.read_line(&mut buffer, self.state.timeouts.join)? {
+                b"OK" => {
+                    // The lengths were checked above
+                    self.state.init_config.ssid = Some(ArrayString::from(ssid).unwrap());
+                    self.state.init_config.password = ArrayString::from(password).unwrap();
+                    return Ok(())
+                }
+                b"ERROR" => return Err(Error::UnexpectedResponse(ATResponse::Error)),
+                b"FAIL" => return Err(Error::JoinError { code: reason }),
+                line if line.starts_with(b"+CWJAP:") => {
+                    if let Some(code) = parse_u8(&line[b"+CWJAP:".len()..]) {
+                        reason = JoinFailReason::from_code(code);
+                    }
+                }
+                // Status messages like WIFI CONNECTED are sent before the result
+                _ => continue
+            }
+        }
+    }
+
+    /**
+      Scans for access points, calling `on_network` with each one found.
+      Returns the number of access points found.
+
+      Scanning takes a few seconds so the join timeout is used while waiting
+      for the results
+    */
+    pub fn scan_networks<F>(&mut self, on_network: F) -> return_type!(usize)
+    where F: FnMut(ApInfo)
+    {
+        self.scan_networks_sized(on_network)
+    }
+
+    /**
+      Like `scan_networks` but stores the SSIDs in `A`, for example
+      `[u8; 16]` to save memory. SSIDs which don't fit are truncated and
+      reported with `ApInfo::ssid_truncated` rather than failing the scan
+    */
+    pub fn scan_networks_sized<A, F>(&mut self, mut on_network: F) -> return_type!(usize)
+    where A: Array<Item = u8> + Copy,
+          F: FnMut(ApInfo<A>)
+    {
+        const PREFIX: &[u8] = b"+CWLAP:";
+        let mut buffer = [0; LONG_LINE_LENGTH];
+        let mut found = 0;
+        self.send_at_command("+CWLAP")?;
+        loop {
+            match self.read_line(&mut buffer, self.state.timeouts.join)? {
+                b"OK" => return Ok(found),
+                b"ERROR" => return Err(Error::UnexpectedResponse(ATResponse::Error)),
+                line if line.starts_with(PREFIX) => {
+                    let info = parse_ap_info(&line[PREFIX.len()..])
+                        .ok_or(Error::InvalidResponse)?;
+                    found += 1;
+                    on_network(info);
+                }
+                _ => continue
+            }
+        }
+    }
+
+    /**
+      Sets the sleep mode the device uses while idle.
+
+      Unlike deep sleep the device stays associated with the access point so
+      no re-initialisation is needed. However, light sleep delays the
+      responses of the device which may require longer timeouts.
+    */
+    pub fn set_sleep_mode(&mut self, mode: SleepMode) -> return_type!(()) {
+        self.send_at_command(mode.command())?;
+        self.wait_for_ok(self.state.timeouts.command)?;
+        self.state.init_config.sleep_mode = Some(mode);
+        Ok(())
+    }
+
+    /**
+      Sets whether configuration commands store their settings in flash
+      using `AT+SYSSTORE`, which is only supported by newer firmware. Storing
+      is enabled by default.
+
+      This affects commands without a `_CUR` or `_DEF` suffix, like the
+      `AT+CWJAP` sent by `join_access_point`. Methods which use the suffixed
+      commands, like `set_country` and `set_baud_rate`, never store their
+      settings regardless. Disabling storing avoids wearing the flash when
+      the device is configured on every start
+    */
+    pub fn set_sysstore(&mut self, persist: bool) -> return_type!(()) {
+        self.send_at_command(if persist {"+SYSSTORE=1"} else {"+SYSSTORE=0"})?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Puts the device in deep sleep for `duration`. The device only wakes up
+      by itself if GPIO16 is connected to RST, in which case it reboots and
+      `wake_reason` can be used to wait for it to start again.
+
+      Everything but the settings stored in flash is lost while sleeping
+    */
+    pub fn deep_sleep(&mut self, duration: Millisecond) -> return_type!(()) {
+        self.begin_command(b"+GSLP=")?;
+        self.send_number(duration.as_millis())?;
+        self.end_command()?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Waits for the device to boot, for example after waking from
+      `deep_sleep`, and returns why it started. Like `power_up`, this also
+      waits for the device to be ready for commands.
+
+      The boot banner is sent at 74880 baud so with other baud rates it is
+      often garbled, in which case `WakeReason::Unknown(None)` is returned
+    */
+    pub fn wake_reason(&mut self) -> return_type!(WakeReason) {
+        let mut banner = [0; BOOT_BANNER_LENGTH];
+        let logged = self.power_up_with_boot_log(&mut banner)?;
+        Ok(WakeReason::from_boot_log(&banner[..logged]))
+    }
+
+    /**
+      Sets the country the device operates in and the range of channels it
+      may use, starting at `start_channel` and including `channel_count`
+      channels.
+
+      `code` must be two upper case letters and the channel range must be
+      within channels 1 to 14. The setting is not stored in flash
+    */
+    pub fn set_country(&mut self, code: &str, start_channel: u8, channel_count: u8)
+        -> return_type!(())
+    {
+        if code.len() != 2 || !code.bytes().all(|byte| byte.is_ascii_uppercase()) {
+            return Err(Error::InvalidArgument("code"));
+        }
+        if !(1..=MAX_WIFI_CHANNEL).contains(&start_channel) {
+            return Err(Error::InvalidArgument("start_channel"));
+        }
+        if !(1..=MAX_WIFI_CHANNEL - start_channel + 1).contains(&channel_count) {
+            return Err(Error::InvalidArgument("channel_count"));
+        }
+
+        self.begin_command(b"+CWCOUNTRY_CUR=1,\"")?;
+        self.send_raw(code.as_bytes())?;
+        self.send_raw(b"\",")?;
+        self.send_number(u32::from(start_channel))?;
+        self.send_raw(b",")?;
+        self.send_number(u32::from(channel_count))?;
+        self.end_command()?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Configures `pin` of the module as an output if `output` is true,
+      otherwise as an input. Only GPIO0 and GPIO2 can be used, the other pins
+      of an ESP-01 are used by the serial port.
+
+      Requires firmware supporting `AT+SYSGPIODIR`
+    */
+    pub fn configure_module_gpio(&mut self, pin: u8, output: bool) -> return_type!(()) {
+        self.send_gpio_command(b"+SYSGPIODIR=", pin, output)
+    }
+
+    /**
+      Sets the level of `pin` of the module, which must have been configured
+      as an output with `configure_module_gpio`
+    */
+    pub fn set_module_gpio(&mut self, pin: u8, level: bool) -> return_type!(()) {
+        self.send_gpio_command(b"+SYSGPIOWRITE=", pin, level)
+    }
+
+    fn send_gpio_command(&mut self, command: &[u8], pin: u8, value: bool)
+        -> return_type!(())
+    {
+        if !MODULE_GPIO_PINS.contains(&pin) {
+            return Err(Error::InvalidArgument("pin"));
+        }
+        self.begin_command(command)?;
+        self.send_number(u32::from(pin))?;
+        self.send_raw(if value { b",1" } else { b",0" })?;
+        self.end_command()?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Reads the country and channel range currently used by the device
+    */
+    pub fn country(&mut self) -> return_type!(CountryConfig) {
+        self.query_value("+CWCOUNTRY_CUR?", b"+CWCOUNTRY_CUR:", |line| {
+            // Format: <policy>,"<code>",<start channel>,<channel count>
+            let mut fields = split_fields(line).skip(1);
+            let code = core::str::from_utf8(unquote(fields.next()?)).ok()?;
+            Some(CountryConfig {
+                code: ArrayString::from(code).ok()?,
+                start_channel: parse_u8(fields.next()?)?,
+                channel_count: parse_u8(fields.next()?)?,
+            })
+        })
+    }
+
+    /**
+      Allows or disallows multiple simultaneous connections. Multiple
+      connections are required for running a server while starting a
+      connection with `start_tcp_connection` requires a single connection
+    */
+    pub fn set_multiplexing(&mut self, enabled: bool) -> return_type!(()) {
+        let (command, mode) = if enabled {
+            ("+CIPMUX=1", MuxMode::Multiple)
+        }
+        else {
+            ("+CIPMUX=0", MuxMode::Single)
+        };
+        self.send_at_command(command)?;
+        self.wait_for_ok(self.state.timeouts.command)?;
+        self.state.mux_mode = mode;
+        self.state.init_config.multiplexing = enabled;
+        Ok(())
+    }
+
+    /**
+      Reads whether multiple connections are enabled from the device and
+      updates the locally tracked mode to match. Useful after the device may
+      have restarted on its own, which resets it to single connection mode
+    */
+    pub fn query_mux(&mut self) -> return_type!(MuxMode) {
+        let mode = self.query_value("+CIPMUX?", b"+CIPMUX:", |line| {
+            match parse_u8(line)? {
+                0 => Some(MuxMode::Single),
+                1 => Some(MuxMode::Multiple),
+                _ => None
+            }
+        })?;
+        self.state.mux_mode = mode;
+        Ok(mode)
+    }
+
+    /**
+      Starts a TCP server listening on `port`. Requires multiple connections
+      to be enabled with `set_multiplexing`
+    */
+    pub fn start_server(&mut self, port: u16) -> return_type!(()) {
+        self.require_mux_mode(MuxMode::Multiple)?;
+        self.begin_command(b"+CIPSERVER=1,")?;
+        self.send_number(u32::from(port))?;
+        self.end_command()?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Limits the number of clients the server accepts at the same time to
+      `connections`, 1 to 5. Must be called before `start_server`. Fewer
+      connections leave more memory for other uses on the device
+    */
+    pub fn set_server_max_connections(&mut self, connections: u8) -> return_type!(()) {
+        if !(1..=MAX_SERVER_CONNECTIONS).contains(&connections) {
+            return Err(Error::InvalidArgument("connections"));
+        }
+        self.begin_command(b"+CIPSERVERMAXCONN=")?;
+        self.send_number(u32::from(connections))?;
+        self.end_command()?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Makes the server close client connections which have been idle for
+      `seconds`, 1 to 7200, so that idle clients don't use up the limited
+      number of connections. 0 keeps idle connections open. This is the same
+      setting as `set_reconnect_policy`
+    */
+    pub fn set_server_timeout(&mut self, seconds: u16) -> return_type!(()) {
+        let policy = match seconds {
+            0 => ReconnectPolicy::KeepOpen,
+            1..=MAX_IDLE_TIMEOUT => ReconnectPolicy::CloseIdleAfter(seconds),
+            _ => return Err(Error::InvalidArgument("seconds"))
+        };
+        self.set_reconnect_policy(policy)
+    }
+
+    /**
+      Opens the connection described by `params` on link `link_id`, 0 to 4.
+      Requires multiple connections to be enabled with `set_multiplexing`.
+
+      If the device can't connect, `Error::ConnectFailed` is returned with
+      the id of the link
+    */
+    pub fn connect_link(&mut self, link_id: u8, params: &ConnectionParams) -> return_type!(()) {
+        params.validate()?;
+        self.require_mux_mode(MuxMode::Multiple)?;
+        if link_id > MAX_LINK_ID {
+            return Err(Error::InvalidArgument("link_id"));
+        }
+        self.send_cipstart(Some(link_id), params)?;
+        self.state.links_in_use |= 1 << link_id;
+        Ok(())
+    }
+
+    /**
+      Like `connect_link` but uses the lowest link which isn't in use and
+      returns it. Returns `Error::NoFreeLink` if all links are in use.
+
+      Links are in use from being opened with `connect_link` or `open_link`
+      until they are closed with `close_link` or `release_link`. Links
+      closed by the remote end stay in use until they are closed locally
+      as well
+    */
+    pub fn open_link(&mut self, params: &ConnectionParams) -> return_type!(LinkId) {
+        let link_id = (0..=MAX_LINK_ID)
+            .find(|id| self.state.links_in_use & (1 << id) == 0)
+            .ok_or(Error::NoFreeLink)?;
+        self.connect_link(link_id, params)?;
+        Ok(LinkId(link_id))
+    }
+
+    /**
+      Closes `link` and makes it available to `open_link` again
+    */
+    pub fn release_link(&mut self, link: LinkId) -> return_type!(()) {
+        self.close_link(link.id())
+    }
+
+    /**
+      Sends `data` as one message over link `link_id` opened with
+      `connect_link` or accepted by the server
+    */
+    pub fn transmit_to_link(&mut self, link_id: u8, data: &[u8]) -> return_type!(()) {
+        self.require_mux_mode(MuxMode::Multiple)?;
+        if link_id > MAX_LINK_ID {
+            return Err(Error::InvalidArgument("link_id"));
+        }
+        self.transmit_parts_to(Some(link_id), &[data], |_| {})
+    }
+
+    /**
+      Closes link `link_id` in multiple connection mode
+    */
+    pub fn close_link(&mut self, link_id: u8) -> return_type!(()) {
+        self.require_mux_mode(MuxMode::Multiple)?;
+        if link_id > MAX_LINK_ID {
+            return Err(Error::InvalidArgument("link_id"));
+        }
+        // The link can't be used anymore even if closing fails
+        self.state.links_in_use &= !(1 << link_id);
+        self.begin_command(b"+CIPCLOSE=")?;
+        self.send_number(u32::from(link_id))?;
+        self.end_command()?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Stops the server started by `start_server`
+    */
+    pub fn stop_server(&mut self) -> return_type!(()) {
+        self.require_mux_mode(MuxMode::Multiple)?;
+        self.send_at_command("+CIPSERVER=0")?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Checks if the device responds to `AT` with `OK`. Unlike most commands,
+      a timeout or an unexpected response is reported as `false`
+    */
+    pub fn is_responsive(&mut self) -> return_type!(bool) {
+        self.send_at_command("")?;
+        match self.wait_for_ok(self.state.timeouts.command) {
+            Ok(()) => Ok(true),
+            Err(Error::RxError(serial::Error::TimedOut))
+                | Err(Error::UnexpectedRaw(..))
+                | Err(Error::UnexpectedResponse(_)) => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+
+    /**
+      Sends `AT${command}` and returns the response of the device, leaving it
+      up to the caller to decide whether it indicates success. Unsolicited
+      `WIFI GOT IP` messages are skipped
+    */
+    pub fn send_command_expect(&mut self, command: &str, timeout: Millisecond)
+        -> return_type!(ATResponse)
+    {
+        self.send_at_command(command)?;
+        loop {
+            match self.wait_for_any(timeout)? {
+                ATResponse::WiFiGotIp => continue,
+                response => return Ok(response)
+            }
+        }
+    }
+
+    /**
+      Sends `AT${command}` without waiting for the response, which can then
+      be polled for with `poll_response`
+    */
+    pub fn send_command(&mut self, command: &str) -> return_type!(()) {
+        self.send_at_command(command)
+    }
+
+    /**
+      Sends `AT${command}` and returns all lines of the response before `OK`,
+      without line terminators or empty lines. Useful for debugging and for
+      commands without a dedicated method.
+
+      Lines longer than the strings in the vector are truncated. If there are
+      more lines than fit, the rest of the response is read and
+      `Error::Capacity` returned. `ERROR` and `FAIL` are returned as
+      `Error::UnexpectedResponse`
+    */
+    pub fn raw_command_lines<A, L>(&mut self, command: &str) -> return_type!(ArrayVec<A>)
+    where A: Array<Item = ArrayString<L>>,
+          L: Array<Item = u8> + Copy
+    {
+        let mut lines = ArrayVec::new();
+        let mut overflow = None;
+        let mut buffer = [0; LONG_LINE_LENGTH];
+        self.send_at_command(command)?;
+        loop {
+            match self.read_line(&mut buffer, self.state.timeouts.command)? {
+                b"OK" => break,
+                b"ERROR" => return Err(Error::UnexpectedResponse(ATResponse::Error)),
+                b"FAIL" => return Err(Error::UnexpectedResponse(ATResponse::Fail)),
+                b"" => continue,
+                line => {
+                    if let Err(e) = lines.try_push(truncated_string(line)) {
+                        overflow = Some(e.simplify());
+                    }
+                }
+            }
+        }
+        match overflow {
+            Some(e) => Err(Error::Capacity(e)),
+            None => Ok(lines)
+        }
+    }
+
+    /**
+      Non-blocking version of waiting for a response. Reads the bytes which
+      have already arrived and returns the response if it is complete,
+      otherwise `WouldBlock`. Partial responses are kept between calls.
+
+      Together with `abort` this allows a wait to be given up, for example
+      when something more important needs the serial port
+    */
+    pub fn poll_response(&mut self) -> nb_return_type!(ATResponse) {
+        if self.state.dry_run {
+            return Ok(ATResponse::Ok);
+        }
+        loop {
+            let mut rx = ObservedRx::new(&mut self.rx, self.state.observer, &mut self.state.stats, &mut self.state.error_codes);
+            let byte = match hal::serial::Read::read(&mut rx) {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+                Err(nb::Error::Other(e)) => {
+                    self.clear_partial_response();
+                    return Err(nb::Error::Other(Error::RxError(serial::Error::Serial(e))));
+                }
+            };
+            // Like `serial::read_until_message`, skip empty lines before the response
+            if self.state.response_received == 0 && (byte == b'\r' || byte == b'\n') {
+                continue;
+            }
+            let ptr = self.state.response_received % AT_RESPONSE_BUFFER_SIZE;
+            self.state.response_buffer[ptr] = byte;
+            self.state.response_received += 1;
+            let end = self.state.response_received % AT_RESPONSE_BUFFER_SIZE;
+            if let Some(response) = parse_at_response(&self.state.response_buffer, end) {
+                self.clear_partial_response();
+                self.state.consecutive_timeouts = 0;
+                return Ok(response);
+            }
+        }
+    }
+
+    /**
+      Gives up on the response currently being waited for. Discards the
+      bytes waiting in the serial receiver and anything kept from a partially
+      received response, so the next command starts from a clean state.
+
+      The device may still send the rest of the response afterwards, which
+      is skipped when waiting for the next one
+    */
+    pub fn abort(&mut self) {
+        self.clear_partial_response();
+        let mut rx = ObservedRx::new(&mut self.rx, self.state.observer, &mut self.state.stats, &mut self.state.error_codes);
+        // Errors like overruns are irrelevant since everything received is
+        // discarded anyway, but stop at them in case they keep occurring
+        while hal::serial::Read::read(&mut rx).is_ok() {}
+    }
+
+    /**
+      Finds the baud rate the device is using by trying each of `candidates`
+      until the device responds to `AT`. Returns the working baud rate.
+
+      `reconfigure` is called with the serial transmitter and receiver to
+      change the baud rate of the host side before each attempt. Returns
+      `Error::BaudRateNotFound` if none of the candidates work, in which
+      case the serial port is left at the last candidate
+    */
+    pub fn autobaud<F>(&mut self, candidates: &[u32], mut reconfigure: F) -> return_type!(u32)
+    where F: FnMut(&mut Tx, &mut Rx, u32)
+    {
+        for &baud_rate in candidates {
+            reconfigure(&mut self.tx, &mut self.rx, baud_rate);
+            for _ in 0..BAUD_PROBE_ATTEMPTS {
+                self.send_at_command("")?;
+                match self.wait_for_ok(BAUD_PROBE_TIMEOUT) {
+                    Ok(()) => return Ok(baud_rate),
+                    Err(Error::TxError(e)) => return Err(Error::TxError(e)),
+                    Err(_) => continue
+                }
+            }
+        }
+        Err(Error::BaudRateNotFound)
+    }
+
+    /**
+      Changes the baud rate and flow control of the device using
+      `AT+UART_CUR`. Once the device has acknowledged the change,
+      `reconfigure` is called with the serial transmitter and receiver to
+      change the host side to match.
+
+      Flow control only works if the RTS and CTS pins of the device are
+      connected and the serial port of the host is set up to use them,
+      which is up to `reconfigure`. Without it, large messages at high baud
+      rates can lose bytes.
+
+      The setting is not stored in flash, so the device goes back to its
+      default baud rate when it is reset
+    */
+    pub fn set_baud_rate<F>(&mut self, baud_rate: u32, flow_control: FlowControl, reconfigure: F)
+        -> return_type!(())
+    where F: FnOnce(&mut Tx, &mut Rx, u32)
+    {
+        if !BAUD_RATES.contains(&baud_rate) {
+            return Err(Error::InvalidArgument("baud_rate"));
+        }
+        self.begin_command(b"+UART_CUR=")?;
+        self.send_number(baud_rate)?;
+        // 8 data bits, 1 stop bit and no parity
+        self.send_raw(b",8,1,0,")?;
+        self.send_number(flow_control.code())?;
+        self.end_command()?;
+        self.wait_for_ok(self.state.timeouts.command)?;
+        reconfigure(&mut self.tx, &mut self.rx, baud_rate);
+        Ok(())
+    }
+
+    /**
+      Reads the versions of the firmware running on the device
+    */
+    pub fn firmware_version(&mut self) -> return_type!(FirmwareVersion) {
+        let version = self.read_firmware_version()?;
+        self.state.firmware_version = Some(version.clone());
+        Ok(version)
+    }
+
+    /**
+      The firmware version last read by `firmware_version`, `self_test` or
+      `attach_verified`, if any
+    */
+    pub fn cached_firmware_version(&self) -> Option<&FirmwareVersion> {
+        self.state.firmware_version.as_ref()
+    }
+
+    /**
+      The number of bytes sent to and received from the device
+    */
+    pub fn stats(&self) -> Stats {
+        self.state.stats
+    }
+
+    /**
+      Sets the byte counters returned by `stats` to zero
+    */
+    pub fn reset_stats(&mut self) {
+        self.state.stats = Stats::default();
+    }
+
+    /**
+      The time between sending the last command and receiving its final
+      response. `None` if no command has completed yet or if the timer does
+      not implement `LongTimer::elapsed`
+    */
+    pub fn last_command_latency(&self) -> Option<Millisecond> {
+        self.state.last_command_latency
+    }
+
+    fn read_firmware_version(&mut self) -> return_type!(FirmwareVersion) {
+        let mut at = None;
+        let mut sdk = None;
+        self.query("+GMR", b"", |line| {
+            if let Some(version) = line.strip_prefix(b"AT version:") {
+                at = Some(truncated_string(version));
+            }
+            else if let Some(version) = line.strip_prefix(b"SDK version:") {
+                sdk = Some(truncated_string(version));
+            }
+            Ok(())
+        })?;
+        match (at, sdk) {
+            (Some(at), Some(sdk)) => Ok(FirmwareVersion { at, sdk }),
+            _ => Err(Error::InvalidResponse)
+        }
+    }
+
+    /**
+      Reads the firmware image version and flash size of the device, as far
+      as the firmware reports them. Fields which can't be read are `None`
+    */
+    pub fn chip_info(&mut self) -> return_type!(ChipInfo) {
+        let mut bin_version: Option<ArrayString<[u8; VERSION_STRING_LENGTH]>> = None;
+        self.query("+GMR", b"Bin version:", |version| {
+            bin_version = Some(truncated_string(version));
+            Ok(())
+        })?;
+        let mut flash_size = bin_version.as_ref()
+            .and_then(|version| parse_flash_size(version.as_bytes()));
+
+        if flash_size.is_none() {
+            // Format: "<partition>",<type>,<subtype>,<address>,<size>
+            let mut end = None;
+            let partitions = self.query("+SYSFLASH?", b"+SYSFLASH:", |line| {
+                let mut fields = split_fields(line).skip(3);
+                let address = fields.next().and_then(parse_hex_u32);
+                let size = fields.next().and_then(parse_hex_u32);
+                if let (Some(address), Some(size)) = (address, size) {
+                    end = max(end, address.checked_add(size));
+                }
+                Ok(())
+            });
+            match partitions {
+                Ok(()) => flash_size = end,
+                // Older firmware doesn't support the command
+                Err(Error::UnexpectedResponse(ATResponse::Error)) => {},
+                Err(e) => return Err(e)
+            }
+        }
+
+        Ok(ChipInfo { bin_version, flash_size })
+    }
+
+    /**
+      Reads the current WiFi mode of the device
+    */
+    pub fn wifi_mode(&mut self) -> return_type!(WifiMode) {
+        self.query_value("+CWMODE?", b"+CWMODE:", |line| {
+            WifiMode::from_code(parse_u8(line)?)
+        })
+    }
+
+    /**
+      Reads the number of bytes of free heap memory on the device.
+
+      The device needs a fair amount of heap for SSL connections, so checking
+      this can explain connections which fail without a clear reason
+    */
+    pub fn free_heap(&mut self) -> return_type!(u32) {
+        self.query_value("+SYSRAM?", b"+SYSRAM:", |line| {
+            // Newer firmware also reports the lowest free heap seen: <free>,<min>
+            parse_u32(split_fields(line).next()?)
+        })
+    }
+
+    /**
+      Checks whether the device is connected to an access point and has an
+      IP address. This is cheaper than querying the connection status and
+      is suitable for polling.
+
+      Uses `AT+CWSTATE?` when supported by the firmware and falls back
+      to `AT+CWJAP?` otherwise
+    */
+    pub fn wifi_connected(&mut self) -> return_type!(bool) {
+        // Format: <state>,"<ssid>" where state 2 means connected with an IP
+        let state = self.query_value("+CWSTATE?", b"+CWSTATE:", |line| {
+            parse_u8(split_fields(line).next()?)
+        });
+        match state {
+            Ok(state) => return Ok(state == 2),
+            Err(Error::UnexpectedResponse(ATResponse::Error)) => {},
+            Err(e) => return Err(e)
+        }
+
+        // Older firmware responds with +CWJAP:<info> when connected and
+        // with No AP otherwise
+        let mut connected = false;
+        self.query("+CWJAP?", b"+CWJAP:", |_| {
+            connected = true;
+            Ok(())
+        })?;
+        Ok(connected)
+    }
+
+    /**
+      Reads the channel and signal strength of the connection to the access
+      point from `AT+CWJAP?`, and whether 802.11n is enabled from
+      `AT+CWSTAPROTO?` if the firmware supports it. Returns `None` if the
+      device is not connected to an access point
+    */
+    pub fn radio_info(&mut self) -> return_type!(Option<RadioInfo>) {
+        // Format: "<ssid>","<bssid>",<channel>,<rssi>, or No AP if not connected
+        let mut link = None;
+        self.query("+CWJAP?", b"+CWJAP:", |line| {
+            link = Some(parse_ap_link(line).ok_or(Error::InvalidResponse)?);
+            Ok(())
+        })?;
+        let (channel, rssi) = match link {
+            Some(link) => link,
+            None => return Ok(None)
+        };
+
+        // A bit mask where bit 2 is 802.11n
+        let protocols = self.query_value("+CWSTAPROTO?", b"+CWSTAPROTO:", parse_u8);
+        let high_throughput = match protocols {
+            Ok(protocols) => Some(protocols & 0b100 != 0),
+            Err(Error::UnexpectedResponse(ATResponse::Error)) => None,
+            Err(e) => return Err(e)
+        };
+        Ok(Some(RadioInfo { channel, rssi, high_throughput }))
+    }
+
+    /**
+      Reads the access point stored in the flash of the device using
+      `AT+CWJAP_DEF?`, and whether the device joins it automatically on
+      startup. Returns `None` if no access point is stored, in which case
+      `join_access_point` is needed
+    */
+    pub fn stored_ap_config(&mut self) -> return_type!(Option<StoredAp>) {
+        // Format: "<ssid>","<bssid>",<channel>,<rssi>, or No AP if none is stored
+        let mut ssid = None;
+        self.query("+CWJAP_DEF?", b"+CWJAP_DEF:", |line| {
+            ssid = Some(parse_stored_ap_ssid(line).ok_or(Error::InvalidResponse)?);
+            Ok(())
+        })?;
+        let ssid = match ssid {
+            Some(ssid) => ssid,
+            None => return Ok(None)
+        };
+
+        let auto_connect = self.query_value("+CWAUTOCONN?", b"+CWAUTOCONN:", |line| {
+            match parse_u8(line)? {
+                0 => Some(false),
+                1 => Some(true),
+                _ => None
+            }
+        });
+        let auto_connect = match auto_connect {
+            Ok(enabled) => Some(enabled),
+            // Older firmware can only set it
+            Err(Error::UnexpectedResponse(ATResponse::Error)) => None,
+            Err(e) => return Err(e)
+        };
+        Ok(Some(StoredAp { ssid, auto_connect }))
+    }
+
+    /**
+      Checks that the device is alive and reads its firmware version and WiFi
+      mode without using the network. Intended as a quick health check for
+      example during production testing
+    */
+    pub fn self_test(&mut self) -> return_type!(SelfTestReport) {
+        let responsive = self.is_responsive()?;
+        if !responsive {
+            return Ok(SelfTestReport { responsive, version: None, mode: None });
+        }
+        Ok(SelfTestReport {
+            responsive,
+            version: self.firmware_version().ok(),
+            mode: self.wifi_mode().ok(),
+        })
+    }
+
+    /**
+      Checks that a device which hasn't been reset is responding, turns off
+      echo and reads the state which would otherwise be set by a reset
+    */
+    fn verify(&mut self) -> return_type!(()) {
+        let report = self.self_test()?;
+        if !report.responsive {
+            return Err(Error::DeviceNotFound);
+        }
+        if report.version.is_none() {
+            return Err(Error::InvalidResponse);
+        }
+        self.disable_echo()?;
+        self.query_mux()?;
+        Ok(())
+    }
+
+    /**
+      Waits up to `timeout` for a station to join or leave the soft access
+      point of the device. Returns `None` if nothing happened.
+
+      Other messages received while waiting are discarded
+    */
+    pub fn poll_ap_event(&mut self, timeout: Millisecond) -> return_type!(Option<ApEvent>) {
+        let mut buffer = [0; LINE_BUFFER_SIZE];
+        loop {
+            let line = match self.poll_line(&mut buffer, timeout)? {
+                Some(line) => line,
+                None => return Ok(None)
+            };
+            if let Some(mac) = line.strip_prefix(b"+STA_CONNECTED:") {
+                let mac = parse_mac(mac).ok_or(Error::InvalidResponse)?;
+                return Ok(Some(ApEvent::StationConnected(mac)));
+            }
+            if let Some(mac) = line.strip_prefix(b"+STA_DISCONNECTED:") {
+                let mac = parse_mac(mac).ok_or(Error::InvalidResponse)?;
+                return Ok(Some(ApEvent::StationDisconnected(mac)));
+            }
+        }
+    }
+
+    /**
+      Waits up to `timeout` for the device to report something, like a
+      message arriving or a connection being opened or closed, and returns
+      the first thing reported. The payload of a message is stored in
+      `buffer`, bytes which don't fit are discarded. Returns `None` if
+      nothing happened.
+
+      This covers everything a server needs to react to, so it can be used
+      as the event loop of one. Other messages received while waiting are
+      discarded
+    */
+    pub fn poll_event(&mut self, buffer: &mut [u8], timeout: Millisecond)
+        -> return_type!(Option<Event>)
+    {
+        let mut line = ArrayVec::<[u8; LINE_BUFFER_SIZE]>::new();
+        loop {
+            let byte = if line.is_empty() {
+                match self.read_byte(timeout) {
+                    Ok(byte) => byte,
+                    Err(Error::RxError(serial::Error::TimedOut)) => return Ok(None),
+                    Err(e) => return Err(e)
+                }
+            }
+            else {
+                self.read_byte(self.state.timeouts.command)?
+            };
+
+            match byte {
+                b'\r' | b'\n' if line.is_empty() => continue,
+                b'\n' => {
+                    let text = line.strip_suffix(b"\r").unwrap_or(&line);
+                    if let Some(event) = parse_event(text) {
+                        return Ok(Some(event));
+                    }
+                    line.clear();
+                }
+                // Lines which don't fit can't be events
+                _ => { line.try_push(byte).ok(); }
+            }
+
+            // Messages are not terminated by a line break
+            if line.as_slice() == b"+IPD," {
+                let header = self.read_ipd_header(self.state.timeouts.command)?;
+                let length = self.read_ipd_payload(header.len, buffer, self.state.timeouts.command)?;
+                return Ok(Some(Event::Data { link_id: header.link_id, length }));
+            }
+        }
+    }
+
+    /**
+      Turns off the device by setting chip_enable to 0. Does nothing if the
+      device was created with `new_without_chip_enable`
+    */
+    pub fn power_down(&mut self) -> return_type!(()) {
+        match self.chip_enable_pin.as_mut() {
+            Some(pin) => pin.set_low().map_err(Error::PinError),
+            None => Ok(())
+        }
+    }
+
+    /**
+      Shuts the device down cleanly before power is cut: leaves transparent
+      mode, closes all open connections, leaves the access point with
+      `AT+CWQAP` and finally calls `power_down`.
+
+      Every step is attempted even if an earlier one fails, and replies
+      saying there was nothing to close are not errors. The device is
+      powered down in any case, after which the first error is returned
+    */
+    pub fn shutdown(&mut self) -> return_type!(()) {
+        let mut result = Ok(());
+        if self.state.transparent {
+            result = result.and(self.exit_transparent_mode());
+        }
+        let closed = match self.state.mux_mode {
+            MuxMode::Single => self.close_connection_if_open(),
+            MuxMode::Multiple => {
+                // Link id 5 closes all links
+                self.state.links_in_use = 0;
+                self.set_status_led(false);
+                self.send_at_command("+CIPCLOSE=5")
+                    .and_then(|_| self.wait_for_ok(self.state.timeouts.command))
+                    .or_else(|e| match e {
+                        Error::UnexpectedResponse(ATResponse::Error) => Ok(()),
+                        e => Err(e)
+                    })
+            }
+        };
+        result = result.and(closed);
+        let left = self.send_at_command("+CWQAP")
+            .and_then(|_| self.wait_for_ok(self.state.timeouts.command));
+        result = result.and(left);
+        let powered_down = self.power_down();
+        result.and(powered_down)
+    }
+
+    /**
+      Resets the device by setting chip_enable to 0 and then back to 1.
+
+      If a reset pin was provided, RST is held low while chip_enable is low.
+      If the device was created with `new_without_chip_enable`, it is reset
+      with `AT+RST` instead
+    */
+    pub fn reset(&mut self) -> return_type!(()) {
+        if self.chip_enable_pin.is_none() {
+            return self.power_up();
+        }
+        self.power_down()?;
+        if let Some(reset_pin) = self.reset_pin.as_mut() {
+            reset_pin.set_low().map_err(Error::PinError)?;
+        }
+        self.timer.start(Millisecond(10));
+        block!(self.timer.wait()).unwrap();
+        if let Some(reset_pin) = self.reset_pin.as_mut() {
+            reset_pin.set_high().map_err(Error::PinError)?;
+        }
+        self.power_up()
+    }
+
+    /**
+      Resets the device and applies the configuration set since it was
+      created again: multiplexing, sleep mode, passive receive and finally
+      the access point joined with `join_access_point`. Settings which were
+      never changed are left at the defaults of the device.
+
+      Useful after the device has restarted unexpectedly. Open connections
+      and servers are not restored
+    */
+    pub fn reinitialize(&mut self) -> return_type!(()) {
+        self.reset()?;
+        self.replay_config()
+    }
+
+    /**
+      Applies the configuration recorded in `init_config` after a restart
+    */
+    fn replay_config(&mut self) -> return_type!(()) {
+        let config = self.state.init_config.clone();
+        if config.multiplexing {
+            self.set_multiplexing(true)?;
+        }
+        if let Some(mode) = config.sleep_mode {
+            self.set_sleep_mode(mode)?;
+        }
+        if config.passive_receive {
+            self.set_passive_receive(true)?;
+        }
+        else if config.receive_paused {
+            self.pause_receive()?;
+        }
+        if let Some(ssid) = config.ssid {
+            self.join_access_point(&ssid, &config.password)?;
+        }
+        Ok(())
+    }
+
+    /**
+      Turns the device back on by setting chip_enable to high. Without
+      chip_enable, the device is restarted with `AT+RST` instead
+    */
+    pub fn power_up(&mut self) -> return_type!(()) {
+        self.power_up_with_boot_log(&mut []).map(|_| ())
+    }
+
+    /**
+      Like `power_up` but stores the bytes the device sends while booting,
+      before it reports `ready`, in `boot_log`. These include the boot
+      messages of the device which can be useful for debugging startup
+      problems. Bytes which don't fit in `boot_log` are discarded.
+
+      Returns the number of bytes stored. The bytes are stored even if an
+      error is returned
+    */
+    pub fn power_up_with_boot_log(&mut self, boot_log: &mut [u8]) -> return_type!(usize) {
+        match self.chip_enable_pin.as_mut() {
+            Some(pin) => pin.set_high().map_err(Error::PinError)?,
+            None => {
+                self.send_at_command("+RST")?;
+                self.wait_for_restart_ok()?;
+            }
+        }
+        self.start_up(boot_log)
+    }
+
+    /**
+      Restarts the device with `AT+RST` so that configuration stored in flash
+      with the `_DEF` commands takes effect, then applies the configuration
+      set since the device was created again like `reinitialize` does.
+
+      Meant to be called once after a batch of persistent configuration
+      changes, since some of them are only read by the device when it starts.
+      Unlike `reset`, this restarts the device even if CHIP_EN is available
+    */
+    pub fn apply_and_reboot(&mut self) -> return_type!(()) {
+        self.send_at_command("+RST")?;
+        self.wait_for_restart_ok()?;
+        self.start_up(&mut [])?;
+        self.replay_config()
+    }
+
+    /**
+      Waits for the `OK` to `AT+RST`. If the device restarted by itself just
+      before, the status messages it sent while starting may still be
+      queued, so `WIFI GOT IP` is skipped
+    */
+    fn wait_for_restart_ok(&mut self) -> return_type!(()) {
+        loop {
+            match self.wait_for_any(self.state.timeouts.command)? {
+                ATResponse::WiFiGotIp => continue,
+                ATResponse::Ok => return Ok(()),
+                other => return Err(Error::UnexpectedResponse(other))
+            }
+        }
+    }
+
+    /**
+      Waits for a device which was just started to become ready and turns
+      off echo. See `power_up_with_boot_log`
+    */
+    fn start_up(&mut self, boot_log: &mut [u8]) -> return_type!(usize) {
+        // The device starts up in single connection mode
+        self.state.mux_mode = MuxMode::Single;
+        self.state.transparent = false;
+        self.state.connection_type = None;
+        self.state.links_in_use = 0;
+
+        let logged = if boot_log.is_empty() && !self.state.verify_reset {
+            0
+        }
+        else {
+            let (logged, ready) = self.read_boot_log(boot_log)?;
+            if self.state.verify_reset && !ready {
+                return Err(Error::ResetFailed);
+            }
+            logged
+        };
+
+        self.wait_for_startup()?;
+
+        self.disable_echo()?;
+
+        Ok(logged)
+    }
+
+    /**
+      Returns true if the device had echo turned on when it was last
+      initialized by `power_up` or `connect_existing`. Some firmware starts
+      with echo already off
+    */
+    pub fn echo_was_on(&self) -> bool {
+        self.state.echo_was_on
+    }
+
+    /**
+      Turns off echo with `ATE0` and waits for the device to process it.
+      Depending on the firmware, the command itself is echoed before the
+      `OK`, in which case the echo is consumed and `echo_was_on` is set
+    */
+    fn disable_echo(&mut self) -> return_type!(()) {
+        self.send_at_command("E0")?;
+        let mut buffer = [0; LINE_BUFFER_SIZE];
+        let mut echoed = false;
+        loop {
+            match self.read_line(&mut buffer, self.state.timeouts.command)? {
+                b"OK" => break,
+                b"ERROR" => return Err(Error::UnexpectedResponse(ATResponse::Error)),
+                line => {
+                    // The echo still ends with the `\r` of the terminator
+                    let line = line.strip_suffix(b"\r").unwrap_or(line);
+                    if line.starts_with(self.state.command_prefix) && line.ends_with(b"E0") {
+                        echoed = true;
+                    }
+                }
+            }
+        }
+        self.state.echo_was_on = echoed;
+        Ok(())
+    }
+
+    /**
+      Waits for the device to join the access point and get an IP address
+      after starting. Timing out is reported as `Error::NotAssociated` or
+      `Error::NoIpAddress` depending on how far it got
+    */
+    fn wait_for_startup(&mut self) -> return_type!(()) {
+        if self.state.dry_run {
+            return Ok(());
+        }
+        // The esp01 sends a bunch of garbage over the serial port before
+        // starting properly, so anything but the status messages is skipped
+        let mut buffer = [0; LINE_BUFFER_SIZE];
+        let mut associated = false;
+        loop {
+            match self.read_line(&mut buffer, self.state.timeouts.startup) {
+                Ok(b"WIFI GOT IP") => return Ok(()),
+                Ok(b"WIFI CONNECTED") => associated = true,
+                Ok(_) => continue,
+                // Expected here unless it was already read with the boot log
+                Err(Error::ModuleRebooted) => continue,
+                Err(Error::RxError(serial::Error::TimedOut)) if associated => {
+                    return Err(Error::NoIpAddress)
+                }
+                Err(Error::RxError(serial::Error::TimedOut)) => {
+                    return Err(Error::NotAssociated)
+                }
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /**
+      Stores the bytes received until the device reports `ready` in `boot_log`,
+      returning the number of bytes stored and whether `ready` was received.
+      Stops early if nothing is received for the startup timeout
+    */
+    fn read_boot_log(&mut self, boot_log: &mut [u8]) -> return_type!((usize, bool)) {
+        if self.state.dry_run {
+            return Ok((0, true));
+        }
+        let mut recent = [0; READY_BANNER.len()];
+        let mut received = 0;
+        let mut logged = 0;
+        loop {
+            let byte = match serial::read_with_timeout(
+                &mut ObservedRx::new(&mut self.rx, self.state.observer, &mut self.state.stats, &mut self.state.error_codes),
+                &mut self.timer,
+                self.state.timeouts.startup
+            ) {
+                Ok(byte) => byte,
+                Err(serial::Error::TimedOut) => return Ok((logged, false)),
+                Err(e) => return Err(Error::RxError(e))
+            };
+
+            if let Some(target) = boot_log.get_mut(logged) {
+                *target = byte;
+                logged += 1;
+            }
+            recent[received % recent.len()] = byte;
+            received += 1;
+            if compare_circular_buffer(&recent, received % recent.len(), READY_BANNER) {
+                return Ok((logged, true));
+            }
+        }
+    }
+
+    pub fn pull_some_current(&mut self) -> return_type!(()) {
+        let pin = match self.chip_enable_pin.as_mut() {
+            Some(pin) => pin,
+            None => return Ok(())
+        };
+        pin.set_high().map_err(Error::PinError)?;
+
+        self.timer.start(Millisecond(500));
+        block!(self.timer.wait()).unwrap();
+        match self.chip_enable_pin.as_mut() {
+            Some(pin) => pin.set_low().map_err(Error::PinError),
+            None => Ok(())
+        }
+    }
+
+    /**
+      Sends the bytes produced by `iter` as one message over the currently
+      open connection.
+
+      The length of the message is computed by running a clone of `iter` to
+      completion before the bytes are sent, so the message never has to
+      be stored in memory. `iter` must therefore produce the same bytes
+      every time it is cloned.
+
+      Returns `Error::EmptyPayload` without sending anything if `iter` is
+      empty
+    */
+    pub fn transmit_from<I>(&mut self, mut iter: I) -> return_type!(())
+    where I: Iterator<Item = u8> + Clone
+    {
+        let length = iter.clone().count();
+        if length == 0 {
+            return Err(Error::EmptyPayload);
+        }
+        let mut sent = 0;
+        loop {
+            let chunk_length = min(length - sent, self.state.max_send_size);
+            self.begin_transmission(None, chunk_length)?;
+            let result = iter.by_ref()
+                .take(chunk_length)
+                .try_for_each(|byte| self.send_payload(&[byte]));
+            self.finish_transmission(chunk_length, result)?;
+
+            sent += chunk_length;
+            if sent >= length {
+                return Ok(());
+            }
+        }
+    }
+
+    /**
+      Asks the device for the longest message it accepts in a single `CIPSEND`
+      and uses that when splitting messages from now on. If the device doesn't
+      report a limit, the default of 2048 bytes is used.
+
+      Returns the limit which will be used
+    */
+    pub fn detect_max_send_size(&mut self) -> return_type!(usize) {
+        let detected = self.query_value("+CIPSEND=?", b"+CIPSEND:", |line| {
+            // The response contains the accepted range, for example `(0-2048)`.
+            // The upper end of it is the largest number
+            line.split(|byte| !byte.is_ascii_digit())
+                .filter_map(parse_u32)
+                .max()
+        });
+        self.state.max_send_size = match detected {
+            Ok(size) if size > 0 => size as usize,
+            Ok(_)
+                | Err(Error::InvalidResponse)
+                | Err(Error::UnexpectedResponse(ATResponse::Error)) => DEFAULT_MAX_SEND_SIZE,
+            Err(e) => return Err(e)
+        };
+        Ok(self.state.max_send_size)
+    }
+
+    /**
+      The longest message which is sent with a single `CIPSEND`. Longer
+      messages are split into several
+    */
+    pub fn max_send_size(&self) -> usize {
+        self.state.max_send_size
+    }
+
+    /**
+      Waits for a message on the currently open connection and copies its
+      payload into `buffer`, returning the number of bytes copied. If the
+      connection is closed by the remote end, 0 is returned.
+
+      Bytes which do not fit in `buffer` are discarded
+    */
+    pub fn receive_data(&mut self, buffer: &mut [u8], timeout: Millisecond)
+        -> return_type!(usize)
+    {
+        if let Incoming::Closed = self.wait_for_incoming(timeout)? {
+            return Ok(0);
+        }
+        self.read_ipd_data(buffer, timeout)
+    }
+
+    /**
+      Like `receive_data` but returns the payload of the message in an
+      `ArrayVec`, for example `ArrayVec<[u8; 64]>`. An empty vector is
+      returned if the connection was closed.
+
+      If the message doesn't fit, it is discarded and `Error::Capacity` is
+      returned
+    */
+    pub fn receive_vec<A>(&mut self, timeout: Millisecond) -> return_type!(ArrayVec<A>)
+    where A: Array<Item = u8>
+    {
+        let mut result = ArrayVec::new();
+        if let Incoming::Closed = self.wait_for_incoming(timeout)? {
+            return Ok(result);
+        }
+        let message_length = self.read_ipd_length(timeout)?;
+
+        // The whole message is read even if it doesn't fit to not leave
+        // the rest of it behind
+        let mut overflow = None;
+        for _ in 0..message_length {
+            let byte = self.read_byte(timeout)?;
+            if let Err(e) = result.try_push(byte) {
+                overflow = Some(e);
+            }
+        }
+        match overflow {
+            Some(e) => Err(e.into()),
+            None => Ok(result)
+        }
+    }
+
+    /**
+      Enables or disables passive receive mode using `AT+CIPRECVMODE`.
+
+      In passive mode the device buffers received data and only notifies of
+      its length, instead of pushing it in `+IPD` messages that the host
+      must keep up with. The data is then read with `recv_data`. Requires
+      firmware which supports `AT+CIPRECVMODE`.
+
+      This sets the mode explicitly, so it also ends a pause started with
+      `pause_receive`
+    */
+    pub fn set_passive_receive(&mut self, enabled: bool) -> return_type!(()) {
+        self.send_at_command(if enabled {"+CIPRECVMODE=1"} else {"+CIPRECVMODE=0"})?;
+        self.wait_for_ok(self.state.timeouts.command)?;
+        self.state.init_config.passive_receive = enabled;
+        self.state.init_config.receive_paused = false;
+        Ok(())
+    }
+
+    /**
+      Stops the device from pushing received data in `+IPD` messages while
+      the host is too busy to handle it, by switching to passive receive
+      mode until `resume_receive` is called. The device keeps the data
+      instead, and once its buffer for a connection is full it stops
+      acknowledging TCP data so the remote end has to wait.
+
+      The buffer holds a few kilobytes per connection depending on the
+      firmware. Over UDP there is no way to make the sender wait, so
+      datagrams which don't fit are dropped. Buffered data can still be
+      read with `recv_data` while paused.
+
+      The pause is restored by `reinitialize`, so it survives the device
+      restarting. Does nothing if passive receive mode was enabled with
+      `set_passive_receive`, and calling `set_passive_receive` ends the
+      pause
+    */
+    pub fn pause_receive(&mut self) -> return_type!(()) {
+        if self.state.init_config.passive_receive {
+            return Ok(());
+        }
+        self.send_at_command("+CIPRECVMODE=1")?;
+        self.wait_for_ok(self.state.timeouts.command)?;
+        self.state.init_config.receive_paused = true;
+        Ok(())
+    }
+
+    /**
+      Lets the device push received data again after `pause_receive`. Data
+      which was buffered while paused is then pushed as well. Does nothing
+      if passive receive mode was enabled with `set_passive_receive`
+    */
+    pub fn resume_receive(&mut self) -> return_type!(()) {
+        if self.state.init_config.passive_receive {
+            return Ok(());
+        }
+        self.send_at_command("+CIPRECVMODE=0")?;
+        self.wait_for_ok(self.state.timeouts.command)?;
+        self.state.init_config.receive_paused = false;
+        Ok(())
+    }
+
+    /**
+      Reads up to `len` bytes buffered by the device in passive receive mode
+      into `buffer` using `AT+CIPRECVDATA` and returns the number of bytes
+      read, which is less than `len` if less data was available.
+
+      Requires single connection mode and `len` to fit in `buffer`
+    */
+    pub fn recv_data(&mut self, len: usize, buffer: &mut [u8]) -> return_type!(usize) {
+        self.require_mux_mode(MuxMode::Single)?;
+        if len == 0 || len > buffer.len() {
+            return Err(Error::InvalidArgument("len"));
+        }
+        self.begin_command(b"+CIPRECVDATA=")?;
+        self.send_number(len as u32)?;
+        self.end_command()?;
+        if self.state.dry_run {
+            return Ok(0);
+        }
+
+        // The reply is `+CIPRECVDATA:<length>,<data>` followed by `OK`
+        self.flush_tx()?;
+        // Long enough for `+CIPRECVDATA:`
+        let mut start = [0; 13];
+        let result = serial::read_until_message(
+            &mut ObservedRx::new(&mut self.rx, self.state.observer, &mut self.state.stats, &mut self.state.error_codes),
+            &mut self.timer,
+            self.state.timeouts.command,
+            &mut start,
+            &mut 0,
+            &|buf, ptr| {
+                if compare_circular_buffer(buf, ptr, b"+CIPRECVDATA:") {
+                    Some(true)
+                }
+                else if compare_circular_buffer(buf, ptr, b"ERROR\r\n") {
+                    Some(false)
+                }
+                else {
+                    None
+                }
+            }
+        );
+        self.track_timeout(&result);
+        if !result.map_err(Error::RxError)? {
+            return Err(Error::UnexpectedResponse(ATResponse::Error));
+        }
+
+        let mut digits = ArrayVec::<[u8; 4]>::new();
+        loop {
+            match self.read_byte(self.state.timeouts.command)? {
+                b',' => break,
+                byte => digits.try_push(byte).map_err(|_| Error::InvalidResponse)?
+            }
+        }
+        let length = parse_u32(&digits).ok_or(Error::InvalidResponse)? as usize;
+        if length > len {
+            return Err(Error::InvalidResponse);
+        }
+        for target in &mut buffer[..length] {
+            *target = self.read_byte(self.state.timeouts.payload)?;
+        }
+        self.wait_for_ok(self.state.timeouts.command)?;
+        Ok(length)
+    }
+
+    /**
+      Receives messages on the currently open connection, calling `sink` with
+      each chunk of data as it arrives, until the connection is closed or no
+      data arrives within `timeout`. Returns the total number of bytes received.
+
+      Unlike `receive_data`, this does not require a buffer large enough for
+      a whole message which makes it suitable for large downloads
+    */
+    pub fn receive_to<F>(&mut self, timeout: Millisecond, sink: F) -> return_type!(usize)
+    where F: FnMut(&[u8])
+    {
+        let mut chunk = [0; RECEIVE_CHUNK_SIZE];
+        self.receive_to_in(timeout, &mut chunk, sink)
+    }
+
+    /**
+      Like `receive_to` but uses `chunk` instead of a buffer on the stack for
+      the chunks passed to `sink`. This allows larger chunks without risking
+      the stack, for example by using a static buffer.
+
+      `chunk` must not be empty
+    */
+    pub fn receive_to_in<F>(&mut self, timeout: Millisecond, chunk: &mut [u8], mut sink: F)
+        -> return_type!(usize)
+    where F: FnMut(&[u8])
+    {
+        if chunk.is_empty() {
+            return Err(Error::InvalidArgument("chunk"));
+        }
+        let mut total = 0;
+        loop {
+            match self.wait_for_incoming(timeout) {
+                Ok(Incoming::Data) => {},
+                Ok(Incoming::Closed) => return Ok(total),
+                Err(Error::RxError(serial::Error::TimedOut)) => return Ok(total),
+                Err(e) => return Err(e)
+            }
+            let message_length = self.read_ipd_length(timeout)?;
+
+            let mut remaining = message_length;
+            while remaining > 0 {
+                let chunk_length = min(remaining, chunk.len());
+                for byte in chunk[..chunk_length].iter_mut() {
+                    *byte = self.read_byte(timeout)?;
+                }
+                sink(&chunk[..chunk_length]);
+                remaining -= chunk_length;
+            }
+            total += message_length;
+        }
+    }
+
+    /**
+      Sends `data` as one message over the currently open connection, calling
+      `on_progress` with the total number of bytes sent each time the device
+      has acknowledged a chunk. Messages longer than `max_send_size` are split
+      into several chunks, so this can be used to show progress or feed a
+      watchdog during large transfers.
+
+      Empty messages can't be sent, `Error::EmptyPayload` is returned for them
+    */
+    pub fn transmit_data_progress<F>(&mut self, data: &[u8], on_progress: F)
+        -> return_type!(())
+    where F: FnMut(usize)
+    {
+        self.transmit_parts_to(None, &[data], on_progress)
+    }
+
+    /**
+      Sends `data` as one message over the currently open connection without
+      waiting for the device to acknowledge it, for high rate UDP telemetry
+      where the round trip of waiting for `SEND OK` costs more than knowing
+      that the message was sent.
+
+      The acknowledgement is still sent by the device. Consecutive calls skip
+      it while waiting for the next prompt and any other command waits for
+      it first, so it is never mistaken for the response to that command.
+      `data` must fit in one message of at most `max_send_size` bytes
+    */
+    pub fn transmit_data_no_ack(&mut self, data: &[u8]) -> return_type!(()) {
+        if data.is_empty() {
+            return Err(Error::EmptyPayload);
+        }
+        if data.len() > self.state.max_send_size {
+            return Err(Error::InvalidArgument("data"));
+        }
+        // The prompt for this message comes after the previous acknowledgement
+        self.state.unacknowledged_send = None;
+        self.begin_transmission(None, data.len())?;
+        let result = self.send_payload(data);
+        if result.is_err() {
+            return self.finish_transmission(data.len(), result);
+        }
+        self.state.unacknowledged_send = Some(data.len());
+        Ok(())
+    }
+
+    /**
+      Receives messages without sending anything, calling `on_frame` with the
+      payload of each one, for devices which only consume data pushed to them
+      over a connection kept open by the device itself. Payloads are copied
+      into `buffer` first, bytes which don't fit are discarded.
+
+      There is no timeout, periods without data are waited out. Listening
+      stops when `on_frame` returns false or when the connection is closed,
+      returning the total number of bytes received
+    */
+    pub fn listen<F>(&mut self, buffer: &mut [u8], mut on_frame: F) -> return_type!(usize)
+    where F: FnMut(&[u8]) -> bool
+    {
+        if self.state.dry_run {
+            // Nothing will ever arrive
+            return Ok(0);
+        }
+        let timeout = self.state.timeouts.command;
+        let mut total = 0;
+        loop {
+            match self.wait_for_incoming(timeout) {
+                Ok(Incoming::Data) => {},
+                Ok(Incoming::Closed) => return Ok(total),
+                Err(Error::RxError(serial::Error::TimedOut)) => continue,
+                Err(e) => return Err(e)
+            }
+            let length = self.read_ipd_data(buffer, timeout)?;
+            total += length;
+            if !on_frame(&buffer[..length]) {
+                return Ok(total);
+            }
+        }
+    }
+
+    /**
+      Starts sending a message of `length` bytes over the currently open
+      connection, for streaming data whose total length is known in advance
+      but which isn't available all at once. The payload is sent with
+      `send_chunk` and the message is finished with `end_send`.
+
+      No other commands may be sent until `end_send` is called since the
+      device treats everything it receives as part of the message. `length`
+      is limited to `max_send_size` and the whole payload has to be sent
+      within the payload timeout
+    */
+    pub fn begin_send(&mut self, length: usize) -> return_type!(()) {
+        if self.state.send_declared.is_some() {
+            return Err(Error::SendInProgress);
+        }
+        if length == 0 {
+            return Err(Error::EmptyPayload);
+        }
+        if length > self.state.max_send_size {
+            return Err(Error::InvalidArgument("length"));
+        }
+        self.begin_transmission(None, length)?;
+        self.state.send_declared = Some(length);
+        self.state.send_sent = 0;
+        Ok(())
+    }
+
+    /**
+      Sends part of the payload of a message started with `begin_send`. Fails
+      with `Error::InvalidArgument` without sending anything if `data` would
+      exceed the declared length.
+
+      If the payload timeout runs out, the message is abandoned and
+      `Error::SendTimedOut` is returned
+    */
+    pub fn send_chunk(&mut self, data: &[u8]) -> return_type!(()) {
+        let declared = self.state.send_declared.ok_or(Error::SendNotStarted)?;
+        if self.state.send_sent + data.len() > declared {
+            return Err(Error::InvalidArgument("data"));
+        }
+        match self.send_payload(data) {
+            Ok(()) => {
+                self.state.send_sent += data.len();
+                Ok(())
+            }
+            Err(e) => {
+                self.state.send_declared = None;
+                self.finish_transmission(declared, Err(e))
+            }
+        }
+    }
+
+    /**
+      Finishes a message started with `begin_send` by waiting for the device
+      to acknowledge it. If fewer bytes than declared were sent, the device
+      gives up on the message and `Error::SendCountMismatch` is returned
+    */
+    pub fn end_send(&mut self) -> return_type!(()) {
+        let declared = self.state.send_declared.take().ok_or(Error::SendNotStarted)?;
+        if self.state.send_sent != declared {
+            // The device responds with SEND FAIL once it stops waiting for
+            // the rest of the message
+            self.wait_for_at_response(&ATResponse::Fail, self.state.timeouts.command).ok();
+            return Err(Error::SendCountMismatch { expected: declared, got: self.state.send_sent });
+        }
+        self.finish_transmission(declared, Ok(()))
+    }
+
+    /**
+      Sends all `parts` as one message. Messages longer than `max_send_size`
+      are split over several `CIPSEND`s
+    */
+    fn transmit_parts(&mut self, parts: &[&[u8]]) -> return_type!(()) {
+        self.transmit_parts_to(None, parts, |_| {})
+    }
+
+    /**
+      Like `transmit_parts` but sends over `link` in multiple connection mode
+      and calls `on_progress` after each acknowledged chunk
+    */
+    fn transmit_parts_to<F>(&mut self, link: Option<u8>, parts: &[&[u8]], mut on_progress: F)
+        -> return_type!(())
+    where F: FnMut(usize)
+    {
+        let length = parts.iter().map(|part| part.len()).sum();
+        if length == 0 {
+            return Err(Error::EmptyPayload);
+        }
+        let mut sent = 0;
+        loop {
+            let chunk_length = min(length - sent, self.state.max_send_size);
+            self.begin_transmission(link, chunk_length)?;
+
+            // Send the pieces of each part which fall within the current chunk
+            let mut result = Ok(());
+            let mut part_start = 0;
+            for part in parts {
+                let start = max(sent, part_start) - part_start;
+                let end = min(sent + chunk_length, part_start + part.len())
+                    .saturating_sub(part_start);
+                part_start += part.len();
+                if start < end {
+                    result = self.send_payload(&part[start..end]);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+            }
+            self.finish_transmission(chunk_length, result)?;
+
+            sent += chunk_length;
+            on_progress(sent);
+            if sent >= length {
+                return Ok(());
+            }
+        }
+    }
+
+    /**
+      Starts sending a message of `length` bytes and waits until the device is
+      ready to receive the payload. Starts the payload timeout
+    */
+    fn begin_transmission(&mut self, link: Option<u8>, length: usize) -> return_type!(()) {
+        self.start_transmission(link, length)?;
+        self.wait_for_prompt(self.state.timeouts.command)?;
+        self.timer.start(self.state.timeouts.payload);
+        Ok(())
+    }
+
+    /**
+      Sends part of the payload of a message. Fails with `Error::SendTimedOut`
+      if the payload timeout, which must be started before the first part is
+      sent, runs out
+    */
+    fn send_payload(&mut self, bytes: &[u8]) -> return_type!(()) {
+        self.observe_tx(bytes);
+        if self.state.dry_run {
+            return Ok(());
+        }
+        match serial::write_all_until_timeout(&mut self.tx, &mut self.timer, bytes) {
+            Ok(()) => {
+                self.count_sent(bytes.len());
+                Ok(())
+            }
+            Err(serial::Error::Serial(e)) => Err(Error::TxError(e)),
+            Err(serial::Error::TimedOut) => Err(Error::SendTimedOut)
+        }
+    }
+
+    /**
+      Waits for the device to acknowledge a message after the payload has been
+      sent, or, if sending the payload timed out, for the device to give up
+      on the partial message
+    */
+    fn finish_transmission(&mut self, length: usize, payload_result: return_type!(()))
+        -> return_type!(())
+    {
+        match payload_result {
+            Ok(()) => self.wait_for_send_ok(length),
+            Err(Error::SendTimedOut) => {
+                // The device responds with SEND FAIL once it stops waiting for
+                // the rest of the message. Whatever it says, it is ready for
+                // new commands afterwards
+                self.wait_for_at_response(&ATResponse::Fail, self.state.timeouts.command).ok();
+                Err(Error::SendTimedOut)
+            }
+            Err(e) => Err(e)
+        }
+    }
+
+    /**
+      Waits for `SEND OK` after a message of `length` bytes. If the device
+      reports the number of bytes it received with `Recv <n> bytes` first,
+      that number must match `length`.
+
+      Over UDP, `SEND OK` only means that the message was queued, so the
+      shorter `udp_ack` timeout is used and not receiving it is not an error
+    */
+    fn wait_for_send_ok(&mut self, length: usize) -> return_type!(()) {
+        if self.state.dry_run {
+            return Ok(());
+        }
+        let udp = self.state.connection_type == Some(ConnectionType::Udp);
+        let timeout = if udp { self.state.timeouts.udp_ack } else { self.state.timeouts.command };
+        let mut buffer = [0; LINE_BUFFER_SIZE];
+        let mut received = None;
+        loop {
+            let line = match self.read_line(&mut buffer, timeout) {
+                Ok(line) => line,
+                Err(Error::RxError(serial::Error::TimedOut)) if udp => return Ok(()),
+                Err(e) => return Err(e)
+            };
+            match line {
+                b"SEND OK" => break,
+                b"SEND FAIL" => return Err(Error::UnexpectedResponse(ATResponse::Fail)),
+                b"ERROR" => return Err(Error::UnexpectedResponse(ATResponse::Error)),
+                line => {
+                    if let Some(count) = line.strip_prefix(b"Recv ")
+                        .and_then(|rest| rest.strip_suffix(b" bytes"))
+                    {
+                        received = parse_u32(count).map(|count| count as usize);
+                    }
+                }
+            }
+        }
+        match received {
+            Some(got) if got != length => {
+                Err(Error::SendCountMismatch { expected: length, got })
+            }
+            _ => Ok(())
+        }
+    }
+
+    /**
+      Opens a connection to `address`:`port`. The connection stays open
+      until `close_connection` is called.
+
+      Requires the device to be in single connection mode
+    */
+    pub fn start_tcp_connection (
+        &mut self,
+        connection_type: ConnectionType,
+        address: &str,
+        port: u16
+    ) -> return_type!(())
+    {
+        self.connect(&ConnectionParams::new(connection_type, address, port))
+    }
+
+    /**
+      Opens the connection described by `params`. The connection stays open
+      until `close_connection` is called.
+
+      `params` are validated before anything is sent to the device.
+      Requires the device to be in single connection mode
+    */
+    pub fn connect(&mut self, params: &ConnectionParams) -> return_type!(()) {
+        params.validate()?;
+        self.require_mux_mode(MuxMode::Single)?;
+
+        if self.state.post_close_delay_pending && self.state.timeouts.post_close_delay.0 > 0 {
+            self.timer.start(self.state.timeouts.post_close_delay);
+            block!(self.timer.wait()).unwrap();
+        }
+        self.state.post_close_delay_pending = false;
+
+        self.set_status_led(true);
+        let result = self.send_cipstart(None, params);
+        match result {
+            Ok(()) => self.state.connection_type = Some(params.kind),
+            Err(_) => self.set_status_led(false),
+        }
+        result
+    }
+
+    /**
+      Opens a connection, on `link` in multiple connection mode. Failures are
+      reported by the device as `CONNECT FAIL`, prefixed with `<link>,` in
+      multiple connection mode, followed by `ERROR`
+    */
+    fn send_cipstart(&mut self, link: Option<u8>, params: &ConnectionParams)
+        -> return_type!(())
+    {
+        self.begin_command(b"+CIPSTART=")?;
+        if let Some(link) = link {
+            self.send_number(u32::from(link))?;
+            self.send_raw(b",")?;
+        }
+        self.send_raw(b"\"")?;
+        self.send_raw(params.kind.as_str().as_bytes())?;
+        self.send_raw(b"\",")?;
+        self.send_quoted_host(params.host)?;
+        self.send_raw(b",")?;
+        self.send_number(u32::from(params.port))?;
+        let keepalive = match params.kind {
+            ConnectionType::Udp => params.keepalive,
+            ConnectionType::Tcp | ConnectionType::Ssl => {
+                params.keepalive.or(self.state.default_keepalive)
+            }
+        };
+        if let Some(keepalive) = keepalive {
+            self.send_raw(b",")?;
+            self.send_number(u32::from(keepalive))?;
+        }
+        self.end_command()?;
+
+        let mut buffer = [0; LINE_BUFFER_SIZE];
+        let mut failed = None;
+        loop {
+            match self.read_line(&mut buffer, self.state.timeouts.command)? {
+                b"OK" => return Ok(()),
+                b"ERROR" => {
+                    return Err(match failed {
+                        Some(link_id) => Error::ConnectFailed { link_id },
+                        None => Error::UnexpectedResponse(ATResponse::Error)
+                    })
+                }
+                b"CONNECT FAIL" => failed = Some(None),
+                line => {
+                    if let Some(id) = line.strip_suffix(b",CONNECT FAIL") {
+                        failed = Some(parse_u8(id));
+                    }
+                }
+            }
+        }
+    }
+
+    fn start_transmission(&mut self, link: Option<u8>, message_length: usize)
+        -> return_type!(())
+    {
+        // Callers split messages into chunks of at most max_send_size, so
+        // this is only a safety net against sending a length the device
+        // rejects
+        if message_length > self.state.max_send_size {
+            return Err(Error::InvalidArgument("length"));
+        }
+        let message_length = u32::try_from(message_length)
+            .map_err(|_| Error::InvalidArgument("length"))?;
+
+        self.begin_command(b"+CIPSEND=")?;
+        if let Some(link) = link {
+            self.send_number(u32::from(link))?;
+            self.send_raw(b",")?;
+        }
+        self.send_number(message_length)?;
+        self.end_command()?;
+        Ok(())
+    }
+
+    /**
+      Sends the "AT${command}" to the device
+    */
+    fn send_at_command(&mut self, command: &str) -> return_type!(()) {
+        self.recover_if_unresponsive()?;
+        self.begin_command(command.as_bytes())?;
+        self.end_command()
+    }
+
+    /**
+      Sends the command prefix followed by `command`, the start of a command
+      whose arguments are sent separately
+    */
+    fn begin_command(&mut self, command: &[u8]) -> return_type!(()) {
+        if let Some(length) = self.state.unacknowledged_send.take() {
+            // Whether the message arrived is of no interest anymore
+            self.wait_for_send_ok(length).ok();
+        }
+        self.state.error_codes.code = None;
+        self.send_raw(self.state.command_prefix)?;
+        self.send_raw(command)
+    }
+
+    /**
+      Sends the terminator which ends a command
+    */
+    fn end_command(&mut self) -> return_type!(()) {
+        self.send_raw(self.state.command_terminator)
     }
 
     fn wait_for_at_response(
@@ -309,63 +3366,625 @@ where Tx: hal::serial::Write<u8>,
         expected_response: &ATResponse,
         timeout: Millisecond
     ) -> return_type!(()) {
-        let mut buffer = [0; AT_RESPONSE_BUFFER_SIZE];
+        if self.state.dry_run {
+            return Ok(());
+        }
+        match self.wait_for_any(timeout)? {
+            ref resp if resp == expected_response => {
+                Ok(())
+            },
+            other => {
+                Err(Error::UnexpectedResponse(other))
+            }
+        }
+    }
+
+    /**
+      Waits for any of the responses in `ATResponse` and returns it
+    */
+    fn wait_for_any(&mut self, timeout: Millisecond) -> return_type!(ATResponse) {
+        if self.state.dry_run {
+            return Ok(ATResponse::Ok);
+        }
+        self.flush_tx()?;
+        // `None` if the device restarted
         let response = serial::read_until_message(
-            &mut self.rx,
+            &mut ObservedRx::new(&mut self.rx, self.state.observer, &mut self.state.stats, &mut self.state.error_codes),
             &mut self.timer,
             timeout,
-            &mut buffer,
-            &parse_at_response
+            &mut self.state.response_buffer,
+            &mut self.state.response_received,
+            &|buf, ptr| {
+                if is_ready_banner(buf, ptr) {
+                    Some(None)
+                }
+                else {
+                    parse_at_response(buf, ptr).map(Some)
+                }
+            }
+        );
+
+        self.track_timeout(&response);
+        match response {
+            Ok(Some(resp)) => {
+                self.clear_partial_response();
+                self.finish_latency();
+                Ok(resp)
+            }
+            Ok(None) => {
+                self.clear_partial_response();
+                Err(Error::ModuleRebooted)
+            }
+            Err(serial::Error::TimedOut) if self.state.response_received != 0 => {
+                // Kept in case the caller waits again
+                Err(unexpected_raw(&self.state.response_buffer, self.state.response_received))
+            }
+            Err(e) => {
+                self.clear_partial_response();
+                Err(Error::RxError(e))
+            }
+        }
+    }
+
+    /**
+      Forgets a partially received response. Stale bytes are zeroed so they
+      can't become part of a match later
+    */
+    fn clear_partial_response(&mut self) {
+        self.state.response_buffer = [0; AT_RESPONSE_BUFFER_SIZE];
+        self.state.response_received = 0;
+    }
+
+    fn wait_for_ok(&mut self, timeout: Millisecond) -> return_type!(()) {
+        self.wait_for_at_response(&ATResponse::Ok, timeout)
+    }
+
+    /**
+      Waits for the `>` prompt which the device sends when it is ready for the
+      payload of a message. Only a `>` at the start of a line counts, so
+      noise like a `busy s...` message before the prompt, or a `>` within
+      other output, is skipped.
+
+      This also covers an `OK\r\n` left over from the previous command
+      arriving together with the prompt as `OK\r\n>`. The `OK` ends a line,
+      so the `>` after it is still the start of one
+    */
+    fn wait_for_prompt(&mut self, timeout: Millisecond) -> return_type!(()) {
+        if self.state.dry_run {
+            return Ok(());
+        }
+        // Long enough for the ready banner and the line break before it.
+        // Before the buffer fills up, the byte before the first one received
+        // is 0
+        let mut buffer = [0; READY_BANNER.len() + 1];
+        let prompted = self.read_until(&mut buffer, timeout, |buf, ptr| {
+            if compare_circular_buffer(buf, ptr, b"\n>")
+                || compare_circular_buffer(buf, ptr, b"\0>")
+            {
+                Some(true)
+            }
+            else if is_ready_banner(buf, ptr) {
+                Some(false)
+            }
+            else {
+                None
+            }
+        })?;
+        if prompted { Ok(()) } else { Err(Error::ModuleRebooted) }
+    }
+
+    /**
+      Reads bytes from the device into `buffer`, which is used as a circular
+      buffer, until `matcher` returns a value or nothing is received within
+      `timeout`. This is the loop the driver itself uses to wait for
+      responses, for building support for protocols or commands which the
+      driver doesn't know about.
+
+      `matcher` is called after every byte with the buffer and the index of
+      the oldest byte in it, `compare_circular_buffer` is useful for
+      matching the end of what was received. Line terminators received
+      before anything else are skipped. Fails with `Error::RxError` if
+      nothing matched before the timeout
+    */
+    pub fn read_until<T, F>(&mut self, buffer: &mut [u8], timeout: Millisecond, matcher: F)
+        -> return_type!(T)
+    where F: Fn(&[u8], usize) -> Option<T>
+    {
+        if buffer.is_empty() {
+            return Err(Error::InvalidArgument("buffer"));
+        }
+        if self.state.dry_run {
+            return Err(Error::RxError(serial::Error::TimedOut));
+        }
+        self.flush_tx()?;
+        let result = serial::read_until_message(
+            &mut ObservedRx::new(&mut self.rx, self.state.observer, &mut self.state.stats, &mut self.state.error_codes),
+            &mut self.timer,
+            timeout,
+            buffer,
+            &mut 0,
+            &matcher
         );
+        result.map_err(Error::RxError)
+    }
+
+    /**
+      Reads an unsolicited line from the device into `buffer`. Returns `None`
+      if no line starts arriving within `timeout`
+    */
+    fn poll_line<'a>(&mut self, buffer: &'a mut [u8], timeout: Millisecond)
+        -> return_type!(Option<&'a [u8]>)
+    {
+        if self.state.dry_run {
+            return Ok(None);
+        }
+        match serial::read_line(
+            &mut ObservedRx::new(&mut self.rx, self.state.observer, &mut self.state.stats, &mut self.state.error_codes),
+            &mut self.timer,
+            timeout,
+            buffer
+        ) {
+            Ok(length) => Ok(Some(&buffer[..length])),
+            Err(serial::Error::TimedOut) => Ok(None),
+            Err(e) => Err(Error::RxError(e))
+        }
+    }
+
+    /**
+      Sends `AT${command}` and waits for `OK`, calling `on_line` with every
+      line of the response that starts with `prefix`. The prefix is removed
+      from the lines
+    */
+    fn query<F>(&mut self, command: &str, prefix: &[u8], on_line: F) -> return_type!(())
+    where F: FnMut(&[u8]) -> return_type!(())
+    {
+        let mut buffer = [0; LINE_BUFFER_SIZE];
+        self.query_in(command, prefix, &mut buffer, on_line)
+    }
 
-        match response {
-            Ok(ref resp) if resp == expected_response => {
-                Ok(())
-            },
-            Ok(other) => {
-                Err(Error::UnexpectedResponse(other))
+    /**
+      Like `query` but reads the lines into `buffer` instead of a buffer on
+      the stack. Lines longer than `buffer` are truncated
+    */
+    fn query_in<F>(&mut self, command: &str, prefix: &[u8], buffer: &mut [u8], mut on_line: F)
+        -> return_type!(())
+    where F: FnMut(&[u8]) -> return_type!(())
+    {
+        self.send_at_command(command)?;
+        loop {
+            match self.read_line(buffer, self.state.timeouts.command)? {
+                b"OK" => return Ok(()),
+                b"ERROR" => return Err(Error::UnexpectedResponse(ATResponse::Error)),
+                b"FAIL" => return Err(Error::UnexpectedResponse(ATResponse::Fail)),
+                line if line.starts_with(prefix) => on_line(&line[prefix.len()..])?,
+                _ => continue
             }
-            Err(e) => {
-                Err(Error::RxError(e))
+        }
+    }
+
+    /**
+      Like `query` but for commands which respond with a single value line
+      which is parsed by `parse`. Returns `Error::InvalidResponse` if the value
+      line is missing or can't be parsed
+    */
+    fn query_value<V, F>(&mut self, command: &str, prefix: &[u8], parse: F)
+        -> return_type!(V)
+    where F: Fn(&[u8]) -> Option<V>
+    {
+        let mut value = None;
+        self.query(command, prefix, |line| {
+            value = Some(parse(line).ok_or(Error::InvalidResponse)?);
+            Ok(())
+        })?;
+        value.ok_or(Error::InvalidResponse)
+    }
+
+    /**
+      Counts consecutive timeouts for `set_auto_recover`
+    */
+    fn track_timeout<V>(&mut self, result: &Result<V, serial::Error<Rx::Error>>) {
+        match result {
+            Err(serial::Error::TimedOut) => {
+                self.state.consecutive_timeouts = self.state.consecutive_timeouts.saturating_add(1);
+                // A command which got no response has no latency
+                self.state.command_sent_at = None;
             }
+            _ => self.state.consecutive_timeouts = 0,
         }
     }
 
-    fn wait_for_ok(&mut self, timeout: Millisecond) -> return_type!(()) {
-        self.wait_for_at_response(&ATResponse::Ok, timeout)
+    /**
+      Resets the device if auto recovery is enabled and enough consecutive
+      timeouts have happened. Commands sent during the reset don't trigger
+      another one
+    */
+    fn recover_if_unresponsive(&mut self) -> return_type!(()) {
+        let limit = match self.state.auto_recover_after {
+            Some(limit) => limit,
+            None => return Ok(())
+        };
+        if self.state.recovering || self.state.consecutive_timeouts < limit {
+            return Ok(());
+        }
+        if !self.take_retry() {
+            return Err(Error::RxError(serial::Error::TimedOut));
+        }
+
+        self.state.consecutive_timeouts = 0;
+        self.state.recovering = true;
+        let result = self.reinitialize();
+        self.state.recovering = false;
+        result
+    }
+
+    fn observe_tx(&self, bytes: &[u8]) {
+        if let Some(observer) = self.state.observer {
+            observer.on_tx(bytes);
+        }
     }
-    fn wait_for_got_ip(&mut self, timeout: Millisecond) -> return_type!(()) {
-        self.wait_for_at_response(&ATResponse::WiFiGotIp, timeout)
+
+    fn set_status_led(&mut self, on: bool) {
+        if let Some(led) = self.status_led.as_mut() {
+            if on {
+                led.set_high().ok();
+            }
+            else {
+                led.set_low().ok();
+            }
+        }
     }
 
-    fn wait_for_prompt(&mut self, timeout: Millisecond) -> return_type!(()) {
-        let mut buffer = [0; 1];
+    /**
+      Updates the state after the connection was closed, either with
+      `close_connection` or by the remote end
+    */
+    fn connection_closed(&mut self) {
+        self.set_status_led(false);
+        self.state.connection_type = None;
+        self.state.post_close_delay_pending = true;
+    }
+
+    fn require_mux_mode(&self, required: MuxMode) -> return_type!(()) {
+        if self.state.mux_mode == required {
+            Ok(())
+        }
+        else {
+            Err(Error::WrongMuxMode { required, actual: self.state.mux_mode })
+        }
+    }
+
+    /**
+      Sends `number` formatted as a decimal number. All numeric arguments,
+      like ports, link ids and message lengths, are sent this way so that
+      they are never truncated
+    */
+    fn send_number(&mut self, number: u32) -> return_type!(()) {
+        let mut buffer = ArrayString::<[_; U32_STRING_LENGTH]>::new();
+        itoa::fmt(&mut buffer, number)?;
+        self.send_raw(buffer.as_bytes())
+    }
+
+    /**
+      Waits for the start of a `+IPD` message or for the connection to be
+      closed
+    */
+    fn wait_for_incoming(&mut self, timeout: Millisecond) -> return_type!(Incoming) {
+        if self.state.dry_run {
+            return Err(Error::RxError(serial::Error::TimedOut));
+        }
+        let mut buffer = [0; 8];
         let result = serial::read_until_message(
-            &mut self.rx,
+            &mut ObservedRx::new(&mut self.rx, self.state.observer, &mut self.state.stats, &mut self.state.error_codes),
             &mut self.timer,
             timeout,
             &mut buffer,
-            &|buf, _ptr| {
-                if buf[0] == '>' as u8 {
-                    Some(())
+            &mut 0,
+            &|buf, ptr| {
+                if compare_circular_buffer(buf, ptr, b"+IPD,") {
+                    Some(Incoming::Data)
+                }
+                else if compare_circular_buffer(buf, ptr, b"CLOSED\r\n") {
+                    Some(Incoming::Closed)
                 }
                 else {
                     None
                 }
             }
         );
+        result.map_err(Error::RxError)
+    }
+
+    /**
+      Reads the rest of a `+IPD` message after `+IPD,` and copies its payload
+      into `buffer`, discarding what doesn't fit. Returns the number of bytes
+      copied
+    */
+    fn read_ipd_data(&mut self, buffer: &mut [u8], timeout: Millisecond)
+        -> return_type!(usize)
+    {
+        let message_length = self.read_ipd_length(timeout)?;
+        self.read_ipd_payload(message_length, buffer, timeout)
+    }
+
+    /**
+      Reads the `message_length` bytes of payload of a `+IPD` message into
+      `buffer`, discarding what doesn't fit. Returns the number of bytes
+      copied
+    */
+    fn read_ipd_payload(&mut self, message_length: usize, buffer: &mut [u8], timeout: Millisecond)
+        -> return_type!(usize)
+    {
+        for i in 0..message_length {
+            let byte = self.read_byte(timeout)?;
+            if let Some(target) = buffer.get_mut(i) {
+                *target = byte;
+            }
+        }
+        Ok(min(message_length, buffer.len()))
+    }
+
+    /**
+      Reads the rest of a `+IPD` header after `+IPD,` up to and including the
+      `:` and returns the length of the message
+    */
+    fn read_ipd_length(&mut self, timeout: Millisecond) -> return_type!(usize) {
+        self.read_ipd_header(timeout).map(|header| header.len)
+    }
+
+    /**
+      Reads and parses the rest of a `+IPD` header after `+IPD,` up to and
+      including the `:`
+    */
+    fn read_ipd_header(&mut self, timeout: Millisecond) -> return_type!(IpdHeader) {
+        let mut header = [0; IPD_HEADER_BUFFER_SIZE];
+        let mut header_length = 0;
+        loop {
+            let byte = self.read_byte(timeout)?;
+            if byte == b':' {
+                break;
+            }
+            if header_length < header.len() {
+                header[header_length] = byte;
+                header_length += 1;
+            }
+        }
+        parse_ipd_header(&header[..header_length]).ok_or(Error::InvalidResponse)
+    }
+
+    fn read_byte(&mut self, timeout: Millisecond) -> return_type!(u8) {
+        if self.state.dry_run {
+            return Err(Error::RxError(serial::Error::TimedOut));
+        }
+        serial::read_with_timeout(
+            &mut ObservedRx::new(&mut self.rx, self.state.observer, &mut self.state.stats, &mut self.state.error_codes),
+            &mut self.timer,
+            timeout
+        )
+            .map_err(Error::RxError)
+    }
+
+    /**
+      Reads a line from the device into `buffer`, returning the part of the
+      buffer containing the line without the line terminator
+    */
+    fn read_line<'a>(&mut self, buffer: &'a mut [u8], timeout: Millisecond)
+        -> return_type!(&'a [u8])
+    {
+        if self.state.dry_run {
+            return Ok(b"OK");
+        }
+        self.flush_tx()?;
+        let result = serial::read_line(
+            &mut ObservedRx::new(&mut self.rx, self.state.observer, &mut self.state.stats, &mut self.state.error_codes),
+            &mut self.timer,
+            timeout,
+            buffer
+        );
+        self.track_timeout(&result);
         match result {
-            Ok(_) => Ok(()),
+            Ok(length) => {
+                match &buffer[..length] {
+                    b"OK" | b"ERROR" => self.finish_latency(),
+                    b"ready" => return Err(Error::ModuleRebooted),
+                    _ => {}
+                }
+                Ok(&buffer[..length])
+            }
             Err(e) => Err(Error::RxError(e))
         }
     }
 
+    /**
+      Sends `bytes` as part of a quoted string argument, escaping the
+      characters which have special meaning to the device
+    */
+    fn send_escaped(&mut self, bytes: &[u8]) -> return_type!(()) {
+        for chunk in bytes.split_inclusive(|byte| needs_escape(*byte)) {
+            let (last, rest) = chunk.split_last().unwrap();
+            if needs_escape(*last) {
+                self.send_raw(rest)?;
+                self.send_raw(&[b'\\', *last])?;
+            }
+            else {
+                self.send_raw(chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+      Sends `host` as the quoted host argument of `AT+CIPSTART`, in the same
+      form as `format_cipstart_host` produces
+    */
+    fn send_quoted_host(&mut self, host: &str) -> return_type!(()) {
+        let host = cipstart_host(host)?;
+        self.send_raw(b"\"")?;
+        self.send_escaped(host.as_bytes())?;
+        self.send_raw(b"\"")
+    }
+
+    /**
+      Waits until everything sent has left the serial port. Called before
+      waiting for a response since some serial implementations buffer the
+      bytes written to them
+    */
+    fn flush_tx(&mut self) -> return_type!(()) {
+        block!(self.tx.flush()).map_err(Error::TxError)
+    }
+
+    /**
+      Sends part of a command. Fails while in transparent mode since the
+      bytes would be sent over the connection instead
+    */
     fn send_raw(&mut self, bytes: &[u8]) -> return_type!(()) {
+        if self.state.transparent {
+            return Err(Error::InTransparentMode);
+        }
+        self.write_raw(bytes)
+    }
+
+    /**
+      Sends `bytes` to the device regardless of the mode it is in
+    */
+    fn write_raw(&mut self, bytes: &[u8]) -> return_type!(()) {
+        self.observe_tx(bytes);
+        if self.state.dry_run {
+            return Ok(());
+        }
+        // Whatever was left of the previous response can't belong to the
+        // response to this command
+        if self.state.response_received != 0 {
+            self.clear_partial_response();
+        }
+        if self.state.command_sent_at.is_none() {
+            self.state.command_sent_at = self.timer.elapsed();
+        }
         match serial::write_all(&mut self.tx, bytes) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.count_sent(bytes.len());
+                Ok(())
+            }
             Err(e) => Err(Error::TxError(e))
         }
     }
+
+    /**
+      Adds `length` bytes to the sent bytes in `stats`
+    */
+    fn count_sent(&mut self, length: usize) {
+        self.state.stats.bytes_sent = self.state.stats.bytes_sent.wrapping_add(length as u32);
+    }
+
+    /**
+      Records the time since the current command was sent as its latency
+    */
+    fn finish_latency(&mut self) {
+        if let Some(sent_at) = self.state.command_sent_at.take() {
+            if let Some(now) = self.timer.elapsed() {
+                self.state.last_command_latency = Some(now.wrapping_sub(sent_at));
+            }
+        }
+    }
+}
+
+/**
+  Finds the flash size in a firmware image version like `2.2.1(ESP8266_1MB)`
+  or `1.7.4(ESP8266_512KB)`
+*/
+fn parse_flash_size(version: &[u8]) -> Option<u32> {
+    let start = version.iter().rposition(|byte| *byte == b'_')? + 1;
+    let size = &version[start..];
+    let digits = size.iter().take_while(|byte| byte.is_ascii_digit()).count();
+    let number = parse_u32(&size[..digits])?;
+    let unit = match &size[digits..] {
+        [b'M', b'B', ..] => 1024 * 1024,
+        [b'K', b'B', ..] => 1024,
+        _ => return None
+    };
+    number.checked_mul(unit)
+}
+
+/**
+  Parses a line reporting an `Event` other than a received message, like
+  `CLOSED`, `1,CONNECT` or `+STA_CONNECTED:"<mac>"`
+*/
+fn parse_event(line: &[u8]) -> Option<Event> {
+    if let Some(mac) = line.strip_prefix(b"+STA_CONNECTED:") {
+        return Some(Event::Station(ApEvent::StationConnected(parse_mac(mac)?)));
+    }
+    if let Some(mac) = line.strip_prefix(b"+STA_DISCONNECTED:") {
+        return Some(Event::Station(ApEvent::StationDisconnected(parse_mac(mac)?)));
+    }
+    let (link_id, status) = match line.iter().position(|byte| *byte == b',') {
+        Some(comma) => (Some(parse_u8(&line[..comma])?), &line[comma + 1..]),
+        None => (None, line)
+    };
+    match status {
+        b"CONNECT" => Some(Event::Connected(link_id)),
+        b"CLOSED" => Some(Event::Closed(link_id)),
+        _ => None
+    }
+}
+
+/**
+  Parses a `+CWLAP` line after the prefix.
+  Format: (<ecn>,"<ssid>",<rssi>,"<mac>",<channel>,...)
+*/
+fn parse_ap_info<A>(line: &[u8]) -> Option<ApInfo<A>>
+where A: Array<Item = u8> + Copy
+{
+    let line = line.strip_prefix(b"(")?;
+    let line = line.strip_suffix(b")").unwrap_or(line);
+    let mut fields = split_fields(line);
+    let encryption = Encryption::from_ecn(parse_u8(fields.next()?)?);
+    let ssid_bytes = unquote(fields.next()?);
+    let ssid: ArrayString<A> = truncated_string(ssid_bytes);
+    Some(ApInfo {
+        encryption,
+        ssid_truncated: ssid.len() < ssid_bytes.len(),
+        ssid,
+        rssi: parse_i8(fields.next()?)?,
+        mac: parse_mac(fields.next()?)?,
+        channel: parse_u8(fields.next()?)?,
+    })
+}
+
+/**
+  Parses a `+CWJAP` line after the prefix, returning the channel and the
+  signal strength. Format: "<ssid>","<bssid>",<channel>,<rssi>,...
+*/
+fn parse_ap_link(line: &[u8]) -> Option<(u8, i8)> {
+    let mut fields = split_fields(line).skip(2);
+    Some((parse_u8(fields.next()?)?, parse_i8(fields.next()?)?))
+}
+
+/**
+  Parses a `+CWJAP_DEF` line after the prefix, returning the SSID.
+  Format: "<ssid>","<bssid>",<channel>,<rssi>
+*/
+fn parse_stored_ap_ssid(line: &[u8]) -> Option<ArrayString<[u8; MAX_SSID_LENGTH]>> {
+    match split_fields(line).next()? {
+        [b'"', ssid @ .., b'"'] => Some(truncated_string(ssid)),
+        _ => None
+    }
+}
+
+/**
+  Builds an `Error::UnexpectedRaw` from the content of the circular `buffer`
+  after `received` bytes have been written to it
+*/
+fn unexpected_raw<R, T, P>(buffer: &[u8; AT_RESPONSE_BUFFER_SIZE], received: usize)
+    -> Error<R, T, P>
+{
+    let length = min(received, AT_RESPONSE_BUFFER_SIZE);
+    let offset = received % AT_RESPONSE_BUFFER_SIZE;
+    let mut bytes = [0; AT_RESPONSE_BUFFER_SIZE];
+    for (i, byte) in bytes.iter_mut().take(length).enumerate() {
+        let circular_index = (AT_RESPONSE_BUFFER_SIZE + offset - length + i)
+            % AT_RESPONSE_BUFFER_SIZE;
+        *byte = buffer[circular_index];
+    }
+    Error::UnexpectedRaw(bytes, length)
 }
 
 /**
@@ -379,6 +3998,9 @@ pub fn parse_at_response(buffer: &[u8], offset: usize) -> Option<ATResponse> {
     else if compare_circular_buffer(buffer, offset, "ERROR\r\n".as_bytes()) {
         Some(ATResponse::Error)
     }
+    else if compare_circular_buffer(buffer, offset, "FAIL\r\n".as_bytes()) {
+        Some(ATResponse::Fail)
+    }
     else if compare_circular_buffer(buffer, offset, "busy p...\r\n".as_bytes()) {
         Some(ATResponse::Busy)
     }
@@ -390,13 +4012,31 @@ pub fn parse_at_response(buffer: &[u8], offset: usize) -> Option<ATResponse> {
     }
 }
 
+/**
+  Returns true for the bytes which have special meaning in the quoted string
+  arguments of commands and have to be escaped with a backslash
+*/
+fn needs_escape(byte: u8) -> bool {
+    byte == b'"' || byte == b',' || byte == b'\\'
+}
+
+/**
+  Checks if a circular buffer ends with the `ready` banner the device sends
+  once it has started. Since some lines end with `ready`, the banner has to
+  be at the start of a line or be the first thing received
+*/
+fn is_ready_banner(buffer: &[u8], offset: usize) -> bool {
+    compare_circular_buffer(buffer, offset, b"\nready\r\n")
+        || compare_circular_buffer(buffer, offset, b"\0ready\r\n")
+}
+
 /**
   Compares the content of a circular buffer with another buffer. The comparison
   is done 'from the back' and if one buffer is longer than the other, only the
   content of the shared bytes is compared.
 
   `offset` is the index of the first byte of the circular buffer
-  ```
+  ```text
   [4,5,0,1,2,3]
        ^- offset
   ```
@@ -420,3 +4060,349 @@ pub fn compare_circular_buffer(
     true
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{contains, started, TestError, GAP};
+
+    const TIMEOUT: Millisecond = Millisecond(100);
+    const MAC: MacAddress = MacAddress([0x5c, 0xcf, 0x7f, 0x12, 0x34, 0x56]);
+
+    fn prompt(chunks: &[&[u8]]) -> Result<(), TestError> {
+        started(chunks).wait_for_prompt(TIMEOUT)
+    }
+
+    #[test]
+    fn binary_payloads_are_sent_unchanged() {
+        let mut data = [0; 256];
+        for (byte, value) in data.iter_mut().zip(0..=255) {
+            *byte = value;
+        }
+        let mut esp = started(&[b"\r\nOK\r\n> ", b"\r\nSEND OK\r\n"]);
+        esp.transmit_parts(&[&data]).unwrap();
+        let written = &esp.tx.written;
+        assert!(written.starts_with(b"AT+CIPSEND=256\r\n"));
+        assert_eq!(&written[b"AT+CIPSEND=256\r\n".len()..], &data[..]);
+
+        let mut esp = started(&[b"\r\nOK\r\n> ", b"\r\nSEND OK\r\n"]);
+        esp.transmit_parts(&[b"\0\xff\r\n\0"]).unwrap();
+        assert!(contains(&esp.tx.written, b"AT+CIPSEND=5\r\n\0\xff\r\n\0"));
+    }
+
+    #[test]
+    fn prompts_are_found() {
+        assert!(prompt(&[b"> "]).is_ok());
+        assert!(prompt(&[b"\r\n> "]).is_ok());
+        assert!(prompt(&[b"\r\n", b">", b" "]).is_ok());
+        assert!(prompt(&[b"busy s...\r\n>"]).is_ok());
+    }
+
+    #[test]
+    fn prompts_arriving_with_the_previous_ok_are_found() {
+        assert!(prompt(&[b"OK\r\n>"]).is_ok());
+        assert!(prompt(&[b"\r\nOK\r\n> "]).is_ok());
+        assert!(prompt(&[b"OK\r\n", b"> "]).is_ok());
+    }
+
+    #[test]
+    fn prompts_must_start_a_line() {
+        match prompt(&[b"data>"]) {
+            Err(Error::RxError(serial::Error::TimedOut)) => {}
+            other => panic!("{:?}", other),
+        }
+        assert!(prompt(&[b"a>b\r\n", b"> "]).is_ok());
+    }
+
+    const GMR: &[u8] = b"\r\nAT version:1.7.4.0(May 11 2020 19:13:04)\r\n\
+        SDK version:3.0.4(9532ceb)\r\n\r\nOK\r\n";
+
+    #[test]
+    fn raw_command_lines_returns_the_response() {
+        let mut esp = started(&[GMR, GMR]);
+        let lines: ArrayVec<[ArrayString<[u8; 64]>; 2]> = esp.raw_command_lines("+GMR").unwrap();
+        assert_eq!(esp.tx.written.as_slice(), b"AT+GMR\r\n");
+        assert_eq!(lines[0].as_str(), "AT version:1.7.4.0(May 11 2020 19:13:04)");
+        assert_eq!(lines[1].as_str(), "SDK version:3.0.4(9532ceb)");
+
+        let lines: ArrayVec<[ArrayString<[u8; 10]>; 2]> = esp.raw_command_lines("+GMR").unwrap();
+        assert_eq!(lines[0].as_str(), "AT version");
+    }
+
+    #[test]
+    fn raw_command_lines_with_too_many_lines() {
+        let mut esp = started(&[GMR, b"\r\n+CIPMUX:0\r\n\r\nOK\r\n"]);
+        let lines: Result<ArrayVec<[ArrayString<[u8; 64]>; 1]>, _> = esp.raw_command_lines("+GMR");
+        match lines {
+            Err(Error::Capacity(_)) => {}
+            other => panic!("{:?}", other),
+        }
+        // The rest of the response was read
+        let lines: ArrayVec<[ArrayString<[u8; 64]>; 1]> =
+            esp.raw_command_lines("+CIPMUX?").unwrap();
+        assert_eq!(lines[0].as_str(), "+CIPMUX:0");
+    }
+
+    #[test]
+    fn raw_command_lines_with_an_error() {
+        let mut esp = started(&[b"\r\nERROR\r\n", b"\r\nOK\r\n"]);
+        let lines: Result<ArrayVec<[ArrayString<[u8; 64]>; 2]>, _> = esp.raw_command_lines("+GMR");
+        match lines {
+            Err(Error::UnexpectedResponse(ATResponse::Error)) => {}
+            other => panic!("{:?}", other),
+        }
+        assert!(esp.is_responsive().unwrap());
+    }
+
+    #[test]
+    fn parses_ap_info() {
+        let info: ApInfo = parse_ap_info(b"(3,\"home\",-50,\"5c:cf:7f:12:34:56\",6)").unwrap();
+        assert_eq!(info.encryption, Encryption::Wpa2Psk);
+        assert_eq!(info.ssid.as_str(), "home");
+        assert!(!info.ssid_truncated);
+        assert_eq!(info.rssi, -50);
+        assert_eq!(info.mac, MAC);
+        assert_eq!(info.channel, 6);
+    }
+
+    #[test]
+    fn over_long_ssids_are_truncated() {
+        let info: ApInfo<[u8; 4]> =
+            parse_ap_info(b"(0,\"network\",-50,\"5c:cf:7f:12:34:56\",6)").unwrap();
+        assert_eq!(info.ssid.as_str(), "netw");
+        assert!(info.ssid_truncated);
+        // A multi byte character which doesn't fit is dropped completely
+        let info: ApInfo<[u8; 4]> =
+            parse_ap_info("(0,\"net\u{e9}\",-50,\"5c:cf:7f:12:34:56\",6)".as_bytes()).unwrap();
+        assert_eq!(info.ssid.as_str(), "net");
+        assert!(info.ssid_truncated);
+    }
+
+    #[test]
+    fn malformed_ap_info() {
+        fn parse(line: &[u8]) -> Option<ApInfo> {
+            parse_ap_info(line)
+        }
+        assert_eq!(parse(b""), None);
+        assert_eq!(parse(b"("), None);
+        assert_eq!(parse(b"3,\"home\",-50,\"5c:cf:7f:12:34:56\",6)"), None);
+        assert_eq!(parse(b"(3,\"home\",-50,\"5c:cf:7f:12:34:56\""), None);
+        assert_eq!(parse(b"(3,\"home\",-500,\"5c:cf:7f:12:34:56\",6)"), None);
+        assert_eq!(parse(b"(3,\"home\",-50,\"5c:cf:7f\",6)"), None);
+        assert_eq!(parse(b"(x,\"home\",-50,\"5c:cf:7f:12:34:56\",6)"), None);
+        assert_eq!(parse(b"(3,\"home\",-50,\"5c:cf:7f:12:34:56\",600)"), None);
+        assert_eq!(parse(b"(\xff\x00,\xfe)"), None);
+    }
+
+    #[test]
+    fn parses_stored_ap_ssids() {
+        let ssid = parse_stored_ap_ssid(b"\"home\",\"5c:cf:7f:12:34:56\",6,-50").unwrap();
+        assert_eq!(ssid.as_str(), "home");
+        // Commas in the SSID are inside the quotes
+        let ssid = parse_stored_ap_ssid(b"\"a,b\",\"5c:cf:7f:12:34:56\",6,-50").unwrap();
+        assert_eq!(ssid.as_str(), "a,b");
+        let ssid = parse_stored_ap_ssid(&[b'"'; 40]).unwrap();
+        assert_eq!(ssid.len(), MAX_SSID_LENGTH);
+    }
+
+    #[test]
+    fn malformed_stored_ap_ssids() {
+        assert_eq!(parse_stored_ap_ssid(b""), None);
+        assert_eq!(parse_stored_ap_ssid(b"\""), None);
+        assert_eq!(parse_stored_ap_ssid(b"\"home"), None);
+        assert_eq!(parse_stored_ap_ssid(b"home,\"5c:cf:7f:12:34:56\",6,-50"), None);
+        assert_eq!(parse_stored_ap_ssid(b"\xff\x00,\xfe"), None);
+    }
+
+    #[test]
+    fn empty_payloads_are_rejected() {
+        let mut esp = started(&[]);
+        assert!(matches!(esp.transmit_data_progress(b"", |_| ()), Err(Error::EmptyPayload)));
+        assert!(matches!(esp.transmit_from(core::iter::empty()), Err(Error::EmptyPayload)));
+        assert!(matches!(esp.transmit_parts(&[b"", b""]), Err(Error::EmptyPayload)));
+        // Nothing was sent to the device
+        assert!(esp.tx.written.is_empty());
+    }
+
+    #[test]
+    fn parses_events() {
+        assert_eq!(parse_event(b"CLOSED"), Some(Event::Closed(None)));
+        assert_eq!(parse_event(b"2,CONNECT"), Some(Event::Connected(Some(2))));
+        assert_eq!(
+            parse_event(b"+STA_CONNECTED:\"5c:cf:7f:12:34:56\""),
+            Some(Event::Station(ApEvent::StationConnected(MAC)))
+        );
+    }
+
+    #[test]
+    fn malformed_events() {
+        assert_eq!(parse_event(b""), None);
+        assert_eq!(parse_event(b"CLOSE"), None);
+        assert_eq!(parse_event(b"CLOSED\r\n"), None);
+        assert_eq!(parse_event(b","), None);
+        assert_eq!(parse_event(b"1,"), None);
+        assert_eq!(parse_event(b"x,CONNECT"), None);
+        assert_eq!(parse_event(b"256,CONNECT"), None);
+        assert_eq!(parse_event(b"+STA_CONNECTED:"), None);
+        assert_eq!(parse_event(b"+STA_DISCONNECTED:\"5c:cf\""), None);
+        assert_eq!(parse_event(b"\xff\x00,\xfe"), None);
+    }
+
+    #[test]
+    fn parses_ap_links() {
+        assert_eq!(parse_ap_link(b"\"home\",\"5c:cf:7f:12:34:56\",6,-50"), Some((6, -50)));
+        // Newer firmware adds more fields after the signal strength
+        assert_eq!(
+            parse_ap_link(b"\"a,b\",\"5c:cf:7f:12:34:56\",11,-71,0,1,3,0,0"),
+            Some((11, -71))
+        );
+    }
+
+    #[test]
+    fn malformed_ap_links() {
+        assert_eq!(parse_ap_link(b""), None);
+        assert_eq!(parse_ap_link(b"\"home\",\"5c:cf:7f:12:34:56\""), None);
+        assert_eq!(parse_ap_link(b"\"home\",\"5c:cf:7f:12:34:56\",6"), None);
+        assert_eq!(parse_ap_link(b"\"home\",\"5c:cf:7f:12:34:56\",256,-50"), None);
+        assert_eq!(parse_ap_link(b"\"home\",\"5c:cf:7f:12:34:56\",6,-500"), None);
+        assert_eq!(parse_ap_link(b"\"home\",\"5c:cf:7f:12:34:56\",,-50"), None);
+        assert_eq!(parse_ap_link(b"\xff\x00,\xfe,\"\","), None);
+    }
+
+    #[test]
+    fn radio_info_of_a_connection() {
+        let mut esp = started(&[
+            b"\r\n+CWJAP:\"home\",\"5c:cf:7f:12:34:56\",6,-50\r\n\r\nOK\r\n",
+            b"\r\n+CWSTAPROTO:7\r\n\r\nOK\r\n",
+        ]);
+        assert_eq!(
+            esp.radio_info().unwrap(),
+            Some(RadioInfo { channel: 6, rssi: -50, high_throughput: Some(true) })
+        );
+
+        let mut esp = started(&[b"\r\nNo AP\r\n\r\nOK\r\n"]);
+        assert_eq!(esp.radio_info().unwrap(), None);
+
+        let mut esp = started(&[b"\r\n+CWJAP:\"home\",\"5c:cf:7f:12:34:56\"\r\n\r\nOK\r\n"]);
+        assert!(matches!(esp.radio_info(), Err(Error::InvalidResponse)));
+    }
+
+    #[test]
+    fn numbers_are_sent_in_full() {
+        let expected: [(u32, &[u8]); 4] = [
+            (0, b"0"),
+            (9, b"9"),
+            (10, b"10"),
+            (u32::MAX, b"4294967295"),
+        ];
+        for &(number, digits) in expected.iter() {
+            let mut esp = started(&[]);
+            esp.send_number(number).unwrap();
+            assert_eq!(esp.tx.written.as_slice(), digits);
+        }
+    }
+
+    fn blank_line(buffer: &[u8], offset: usize) -> Option<()> {
+        if compare_circular_buffer(buffer, offset, b"\r\n\r\n") { Some(()) } else { None }
+    }
+
+    #[test]
+    fn read_until_matches_delimiters_split_across_reads() {
+        let mut esp = started(&[b"HTTP/1.1 200 OK\r\n\r", b"\n"]);
+        let mut buffer = [0; 4];
+        assert!(esp.read_until(&mut buffer, TIMEOUT, blank_line).is_ok());
+    }
+
+    #[test]
+    fn read_until_times_out() {
+        let mut buffer = [0; 4];
+        let mut esp = started(&[b"HTTP/1.1 200 OK\r\n"]);
+        match esp.read_until(&mut buffer, TIMEOUT, blank_line) {
+            Err(Error::RxError(serial::Error::TimedOut)) => {}
+            other => panic!("{:?}", other),
+        }
+        // The rest of the delimiter arrives too late
+        let mut esp = started(&[b"HTTP/1.1 200 OK\r\n\r", b"\n"]);
+        match esp.read_until(&mut buffer, Millisecond(GAP / 2), blank_line) {
+            Err(Error::RxError(serial::Error::TimedOut)) => {}
+            other => panic!("{:?}", other),
+        }
+        match esp.read_until(&mut [], TIMEOUT, blank_line) {
+            Err(Error::InvalidArgument("buffer")) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    const REBOOT: &[u8] = b"\r\n ets Jan  8 2013,rst cause:2, boot mode:(3,7)\r\n\r\nready\r\n";
+
+    #[test]
+    fn reboots_are_reported_while_waiting() {
+        match prompt(&[REBOOT]) {
+            Err(Error::ModuleRebooted) => {}
+            other => panic!("{:?}", other),
+        }
+        match started(&[REBOOT]).wait_for_ok(TIMEOUT) {
+            Err(Error::ModuleRebooted) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn lines_ending_in_ready_are_not_reboots() {
+        assert!(started(&[b"\r\nalready\r\n", b"\r\nOK\r\n"]).wait_for_ok(TIMEOUT).is_ok());
+    }
+
+    #[test]
+    fn the_longest_message_length_is_accepted() {
+        let mut esp = started(&[b"\r\nOK\r\n> ", b"\r\nSEND OK\r\n"]);
+        let max = esp.max_send_size();
+        assert!(matches!(esp.start_transmission(None, max + 1), Err(Error::InvalidArgument("length"))));
+        assert!(matches!(esp.begin_send(max + 1), Err(Error::InvalidArgument("length"))));
+        assert!(esp.tx.written.is_empty());
+
+        let data = [0x55; 2048];
+        esp.transmit_data_progress(&data[..max], |_| ()).unwrap();
+        let command = b"AT+CIPSEND=2048\r\n";
+        assert_eq!(&esp.tx.written[..command.len()], command);
+        assert_eq!(&esp.tx.written[command.len()..], &data[..max]);
+    }
+
+    #[test]
+    fn receiving_is_paused_and_resumed() {
+        let mut esp = started(&[b"\r\nOK\r\n", b"\r\nOK\r\n"]);
+        esp.pause_receive().unwrap();
+        assert_eq!(esp.tx.written.as_slice(), b"AT+CIPRECVMODE=1\r\n");
+        esp.tx.written.clear();
+        esp.resume_receive().unwrap();
+        assert_eq!(esp.tx.written.as_slice(), b"AT+CIPRECVMODE=0\r\n");
+
+        // Passive receive mode is left alone
+        let mut esp = started(&[]);
+        esp.state.init_config.passive_receive = true;
+        esp.pause_receive().unwrap();
+        esp.resume_receive().unwrap();
+        assert!(esp.tx.written.is_empty());
+    }
+
+    #[test]
+    fn paused_receiving_is_replayed() {
+        const OK: &[u8] = b"\r\nOK\r\n";
+        let mut esp = started(&[OK, OK, OK, OK, OK]);
+        esp.pause_receive().unwrap();
+        esp.tx.written.clear();
+        esp.replay_config().unwrap();
+        assert_eq!(esp.tx.written.as_slice(), b"AT+CIPRECVMODE=1\r\n");
+
+        // Nothing is replayed once receiving was resumed
+        esp.resume_receive().unwrap();
+        esp.tx.written.clear();
+        esp.replay_config().unwrap();
+        assert!(esp.tx.written.is_empty());
+
+        esp.pause_receive().unwrap();
+        esp.set_passive_receive(false).unwrap();
+        esp.tx.written.clear();
+        esp.replay_config().unwrap();
+        assert!(esp.tx.written.is_empty());
+    }
+}
@@ -0,0 +1,458 @@
+use embedded_hal as hal;
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use arrayvec::ArrayVec;
+
+use crate::{LongTimer, Millisecond};
+use crate::parse::parse_u32;
+
+/// Bytes the simulated device can have queued for the driver
+const OUTPUT_SIZE: usize = 2048;
+/// Longest command the simulated device accepts
+const COMMAND_SIZE: usize = 256;
+
+/**
+  Behaviour which differs between firmware versions, to make sure that the
+  driver handles all of them
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Quirks {
+    /// Responses start with an empty line, `\r\nOK\r\n` rather than `OK\r\n`
+    pub leading_crlf: bool,
+    /// Commands are echoed back until `ATE0` is received, which is also
+    /// the case again after a restart
+    pub echo: bool,
+    /// `Recv <n> bytes` is sent before `SEND OK`
+    pub report_received: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self { leading_crlf: true, echo: true, report_received: true }
+    }
+}
+
+struct State {
+    quirks: Quirks,
+    echo: bool,
+    multiplexing: bool,
+    fail_next: bool,
+    command: ArrayVec<[u8; COMMAND_SIZE]>,
+    /// Bytes of a message payload which are still expected after `CIPSEND`
+    payload_remaining: usize,
+    payload_length: usize,
+    output: ArrayVec<[u8; OUTPUT_SIZE]>,
+    output_read: usize,
+}
+
+impl State {
+    fn push(&mut self, bytes: &[u8]) {
+        // Like a real device, output which doesn't fit is lost
+        for &byte in bytes {
+            if self.output.try_push(byte).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn push_number(&mut self, number: usize) {
+        let mut digits = arrayvec::ArrayString::<[u8; 20]>::new();
+        itoa::fmt(&mut digits, number).unwrap();
+        self.push(digits.as_bytes());
+    }
+
+    /// Sends a response line, with the empty line before it if the firmware
+    /// does that
+    fn respond(&mut self, line: &[u8]) {
+        if self.quirks.leading_crlf {
+            self.push(b"\r\n");
+        }
+        self.push(line);
+        self.push(b"\r\n");
+    }
+
+    fn boot(&mut self) {
+        self.echo = self.quirks.echo;
+        self.multiplexing = false;
+        self.push(b"\r\n ets Jan  8 2013,rst cause:2, boot mode:(3,7)\r\n\r\n");
+        self.push(b"ready\r\n");
+        self.push(b"WIFI CONNECTED\r\nWIFI GOT IP\r\n");
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.payload_remaining > 0 {
+            self.payload_remaining -= 1;
+            if self.payload_remaining == 0 {
+                // The line with the prompt is ended first
+                self.push(b"\r\n");
+                if self.quirks.report_received {
+                    self.push(b"Recv ");
+                    self.push_number(self.payload_length);
+                    self.push(b" bytes\r\n");
+                }
+                self.respond(b"SEND OK");
+            }
+            return;
+        }
+        if byte != b'\n' {
+            self.command.try_push(byte).ok();
+            return;
+        }
+        let command = self.command.clone();
+        self.command.clear();
+        let command = command.strip_suffix(b"\r").unwrap_or(&command);
+        if self.echo {
+            self.push(command);
+            self.push(b"\r\r\n");
+        }
+        self.execute(command);
+    }
+
+    fn execute(&mut self, command: &[u8]) {
+        if core::mem::replace(&mut self.fail_next, false) {
+            self.respond(b"ERROR");
+            return;
+        }
+        match command {
+            b"ATE0" => self.echo = false,
+            b"ATE1" => self.echo = true,
+            b"AT+RST" => {
+                self.respond(b"OK");
+                self.boot();
+                return;
+            }
+            b"AT+GMR" => {
+                self.respond(b"AT version:1.7.4.0(May 11 2020 19:13:04)");
+                self.push(b"SDK version:3.0.4(9532ceb)\r\n");
+            }
+            b"AT+CIPMUX?" => {
+                self.respond(if self.multiplexing {b"+CIPMUX:1"} else {b"+CIPMUX:0"});
+            }
+            b"AT+CIPMUX=0" => self.multiplexing = false,
+            b"AT+CIPMUX=1" => self.multiplexing = true,
+            b"AT+CIPCLOSE" => self.respond(b"CLOSED"),
+            _ if command.starts_with(b"AT+CIPSTART=") => {
+                if self.multiplexing {
+                    let link = command.get(b"AT+CIPSTART=".len()).cloned().unwrap_or(b'0');
+                    if self.quirks.leading_crlf {
+                        self.push(b"\r\n");
+                    }
+                    self.push(&[link]);
+                    self.push(b",CONNECT\r\n");
+                }
+                else {
+                    self.respond(b"CONNECT");
+                }
+            }
+            _ if command.starts_with(b"AT+CIPSEND=") => {
+                // The length is the last argument, after the link id if any
+                let length = command.rsplit(|byte| *byte == b',' || *byte == b'=').next()
+                    .and_then(parse_u32)
+                    .unwrap_or(0) as usize;
+                self.respond(b"OK");
+                self.push(b"> ");
+                self.payload_length = length;
+                self.payload_remaining = length;
+                return;
+            }
+            _ => {}
+        }
+        self.respond(b"OK");
+    }
+}
+
+/**
+  A simulated ESP8266 which answers commands like the real firmware does,
+  with configurable `Quirks`, for testing code using the driver without
+  hardware. Only enabled with the `mock` feature.
+
+  The serial port halves are created with `tx` and `rx`, for example
+
+  ```rust,ignore
+  let device = MockEsp::new(Quirks::default());
+  let mut esp = Esp8266::<_, _, _, NoPin>::new_without_chip_enable(
+      device.tx(), device.rx(), MockTimer::new()
+  )?;
+  ```
+
+  Commands which aren't simulated are answered with `OK`
+*/
+pub struct MockEsp {
+    state: RefCell<State>,
+}
+
+impl MockEsp {
+    pub fn new(quirks: Quirks) -> Self {
+        Self {
+            state: RefCell::new(State {
+                quirks,
+                echo: quirks.echo,
+                multiplexing: false,
+                fail_next: false,
+                command: ArrayVec::new(),
+                payload_remaining: 0,
+                payload_length: 0,
+                output: ArrayVec::new(),
+                output_read: 0,
+            })
+        }
+    }
+
+    pub fn tx(&self) -> MockTx<'_> {
+        MockTx { device: self }
+    }
+
+    pub fn rx(&self) -> MockRx<'_> {
+        MockRx { device: self }
+    }
+
+    /**
+      Makes the device answer the next command with `ERROR`
+    */
+    pub fn fail_next(&self) {
+        self.state.borrow_mut().fail_next = true;
+    }
+
+    /**
+      Makes the device receive `data` from the remote end, on `link` in
+      multiple connection mode
+    */
+    pub fn push_ipd(&self, link: Option<u8>, data: &[u8]) {
+        let mut state = self.state.borrow_mut();
+        state.push(b"\r\n+IPD,");
+        if let Some(link) = link {
+            state.push_number(usize::from(link));
+            state.push(b",");
+        }
+        state.push_number(data.len());
+        state.push(b":");
+        state.push(data);
+    }
+
+    /**
+      Makes the remote end close the connection, `link` in multiple
+      connection mode
+    */
+    pub fn push_closed(&self, link: Option<u8>) {
+        let mut state = self.state.borrow_mut();
+        if let Some(link) = link {
+            state.push_number(usize::from(link));
+            state.push(b",");
+        }
+        state.push(b"CLOSED\r\n");
+    }
+
+    /**
+      Makes the device restart as if it browned out, sending its boot
+      messages and `ready` banner
+    */
+    pub fn reboot(&self) {
+        self.state.borrow_mut().boot();
+    }
+}
+
+/**
+  Serial transmitter to a `MockEsp`
+*/
+pub struct MockTx<'a> {
+    device: &'a MockEsp,
+}
+
+impl<'a> hal::serial::Write<u8> for MockTx<'a> {
+    type Error = Infallible;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        self.device.state.borrow_mut().receive(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/**
+  Serial receiver from a `MockEsp`
+*/
+pub struct MockRx<'a> {
+    device: &'a MockEsp,
+}
+
+impl<'a> hal::serial::Read<u8> for MockRx<'a> {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        let mut state = self.device.state.borrow_mut();
+        match state.output.get(state.output_read).cloned() {
+            Some(byte) => {
+                state.output_read += 1;
+                if state.output_read == state.output.len() {
+                    state.output.clear();
+                    state.output_read = 0;
+                }
+                Ok(byte)
+            }
+            None => Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+/**
+  Timer for use with `MockEsp` where every call to `wait` counts as one
+  millisecond, so timeouts run out as soon as the simulated device has
+  nothing more to say
+*/
+#[derive(Debug, Default)]
+pub struct MockTimer {
+    remaining: u32,
+}
+
+impl MockTimer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LongTimer for MockTimer {
+    fn wait(&mut self) -> nb::Result<(), Infallible> {
+        match self.remaining {
+            0 => Ok(()),
+            _ => {
+                self.remaining -= 1;
+                Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+
+    fn start(&mut self, Millisecond(duration): Millisecond) {
+        self.remaining = duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{ATResponse, ConnectionParams, ConnectionType, Error, Esp8266, Event, NoPin};
+
+    type Esp<'a> = Esp8266<MockTx<'a>, MockRx<'a>, MockTimer, NoPin>;
+
+    const TIMEOUT: Millisecond = Millisecond(100);
+
+    /// Every combination of quirks
+    fn all_quirks() -> impl Iterator<Item = Quirks> {
+        (0..8).map(|bits| Quirks {
+            leading_crlf: bits & 1 != 0,
+            echo: bits & 2 != 0,
+            report_received: bits & 4 != 0,
+        })
+    }
+
+    fn start(device: &MockEsp) -> Esp<'_> {
+        Esp8266::new_without_chip_enable(device.tx(), device.rx(), MockTimer::new())
+            .unwrap_or_else(|e| panic!("starting failed: {:?}", e))
+    }
+
+    #[test]
+    fn commands_succeed_with_every_quirk() {
+        for quirks in all_quirks() {
+            let device = MockEsp::new(quirks);
+            let mut esp = start(&device);
+            assert!(esp.is_responsive().unwrap(), "{:?}", quirks);
+            assert_eq!(esp.send_command_expect("", TIMEOUT).unwrap(), ATResponse::Ok);
+        }
+    }
+
+    #[test]
+    fn errors_are_reported_with_every_quirk() {
+        for quirks in all_quirks() {
+            let device = MockEsp::new(quirks);
+            let mut esp = start(&device);
+            device.fail_next();
+            assert_eq!(esp.send_command_expect("", TIMEOUT).unwrap(), ATResponse::Error);
+            assert!(esp.is_responsive().unwrap(), "{:?}", quirks);
+        }
+    }
+
+    #[test]
+    fn echo_is_turned_off_again_after_a_reset() {
+        let device = MockEsp::new(Quirks::default());
+        let mut esp = start(&device);
+        assert!(esp.echo_was_on());
+        esp.reset().unwrap();
+        assert!(esp.echo_was_on());
+        assert!(esp.is_responsive().unwrap());
+
+        let device = MockEsp::new(Quirks { echo: false, ..Quirks::default() });
+        let esp = start(&device);
+        assert!(!esp.echo_was_on());
+    }
+
+    #[test]
+    fn messages_are_sent_with_every_quirk() {
+        for quirks in all_quirks() {
+            let device = MockEsp::new(quirks);
+            let mut esp = start(&device);
+            let mut connection = esp.open(&ConnectionParams::new(ConnectionType::Tcp, "10.0.0.1", 80))
+                .unwrap_or_else(|e| panic!("{:?}: {:?}", quirks, e));
+            connection.write(b"hello").unwrap_or_else(|e| panic!("{:?}: {:?}", quirks, e));
+            connection.write(b"again").unwrap_or_else(|e| panic!("{:?}: {:?}", quirks, e));
+            connection.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn messages_are_received_with_every_quirk() {
+        for quirks in all_quirks() {
+            let device = MockEsp::new(quirks);
+            let mut esp = start(&device);
+            esp.connect(&ConnectionParams::new(ConnectionType::Tcp, "10.0.0.1", 80)).unwrap();
+            device.push_ipd(None, b"hello");
+            let mut buffer = [0; 16];
+            assert_eq!(esp.receive_data(&mut buffer, TIMEOUT).unwrap(), 5, "{:?}", quirks);
+            assert_eq!(&buffer[..5], b"hello");
+            device.push_closed(None);
+            assert_eq!(esp.receive_data(&mut buffer, TIMEOUT).unwrap(), 0, "{:?}", quirks);
+        }
+    }
+
+    #[test]
+    fn multiplexed_messages_are_received_with_every_quirk() {
+        for quirks in all_quirks() {
+            let device = MockEsp::new(quirks);
+            let mut esp = start(&device);
+            esp.set_multiplexing(true).unwrap();
+            let params = ConnectionParams::new(ConnectionType::Tcp, "10.0.0.1", 80);
+            esp.connect_link(3, &params).unwrap_or_else(|e| panic!("{:?}: {:?}", quirks, e));
+
+            let mut buffer = [0; 16];
+            device.push_ipd(Some(3), b"hello");
+            assert_eq!(
+                esp.poll_event(&mut buffer, TIMEOUT).unwrap(),
+                Some(Event::Data { link_id: Some(3), length: 5 }),
+                "{:?}", quirks
+            );
+            assert_eq!(&buffer[..5], b"hello");
+            device.push_closed(Some(3));
+            assert_eq!(esp.poll_event(&mut buffer, TIMEOUT).unwrap(), Some(Event::Closed(Some(3))));
+            assert_eq!(esp.poll_event(&mut buffer, TIMEOUT).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn reboots_are_detected_with_every_quirk() {
+        for quirks in all_quirks() {
+            let device = MockEsp::new(quirks);
+            let mut esp = start(&device);
+            device.reboot();
+            match esp.send_command_expect("", TIMEOUT) {
+                Err(Error::ModuleRebooted) => {}
+                other => panic!("{:?}: {:?}", quirks, other),
+            }
+            esp.reinitialize().unwrap();
+            assert!(esp.is_responsive().unwrap(), "{:?}", quirks);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use embedded_hal as hal;
+
+use arrayvec::ArrayVec;
+
+use crate::Stats;
+use crate::parse::parse_hex_u32;
+
+/// The `ERR CODE:0x` prefix and 8 hex digits
+const ERROR_CODE_LINE_LENGTH: usize = 19;
+
+/**
+  Receives every byte sent to and received from the device, for example to
+  log the communication when debugging.
+
+  The callbacks are called from the I/O functions of the driver so they
+  should return quickly. Both do nothing by default
+*/
+pub trait Observer {
+    /// Called with bytes before they are sent to the device
+    fn on_tx(&self, _bytes: &[u8]) {}
+    /// Called with bytes after they have been received from the device
+    fn on_rx(&self, _bytes: &[u8]) {}
+}
+
+/**
+  Picks the `ERR CODE:0x<code>` lines, which newer firmware sends before
+  `ERROR` if enabled with `AT+SYSLOG=1`, out of the received bytes
+*/
+#[derive(Debug, Default)]
+pub(crate) struct ErrorCodeScanner {
+    line: ArrayVec<[u8; ERROR_CODE_LINE_LENGTH]>,
+    /// Set when the current line is too long to be an error code
+    overflowed: bool,
+    pub code: Option<u32>,
+}
+
+impl ErrorCodeScanner {
+    fn feed(&mut self, byte: u8) {
+        match byte {
+            b'\n' => {
+                if !self.overflowed {
+                    if let Some(code) = parse_error_code(&self.line) {
+                        self.code = Some(code);
+                    }
+                }
+                self.line.clear();
+                self.overflowed = false;
+            }
+            b'\r' => {}
+            _ => {
+                if self.line.try_push(byte).is_err() {
+                    self.overflowed = true;
+                }
+            }
+        }
+    }
+}
+
+fn parse_error_code(line: &[u8]) -> Option<u32> {
+    parse_hex_u32(line.strip_prefix(b"ERR CODE:")?)
+}
+
+/**
+  Serial receiver which reports the bytes read from `rx` to `observer`,
+  counts them in `stats` and looks for error codes with `errors`
+*/
+pub struct ObservedRx<'a, Rx> {
+    rx: &'a mut Rx,
+    observer: Option<&'static dyn Observer>,
+    stats: &'a mut Stats,
+    errors: &'a mut ErrorCodeScanner,
+}
+
+impl<'a, Rx> ObservedRx<'a, Rx> {
+    pub(crate) fn new(
+        rx: &'a mut Rx,
+        observer: Option<&'static dyn Observer>,
+        stats: &'a mut Stats,
+        errors: &'a mut ErrorCodeScanner
+    ) -> Self {
+        Self { rx, observer, stats, errors }
+    }
+}
+
+impl<'a, Rx> hal::serial::Read<u8> for ObservedRx<'a, Rx>
+where Rx: hal::serial::Read<u8>
+{
+    type Error = Rx::Error;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        let byte = self.rx.read()?;
+        self.stats.bytes_received = self.stats.bytes_received.wrapping_add(1);
+        self.errors.feed(byte);
+        if let Some(observer) = self.observer {
+            observer.on_rx(&[byte]);
+        }
+        Ok(byte)
+    }
+}
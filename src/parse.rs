@@ -0,0 +1,374 @@
+use core::convert::TryFrom;
+use core::fmt;
+
+use arrayvec::{Array, ArrayString};
+
+/**
+  Parses `bytes` as a decimal number, returning `None` if it contains anything
+  other than digits or if the number doesn't fit in a `u32`
+*/
+pub fn parse_u32(bytes: &[u8]) -> Option<u32> {
+    if bytes.is_empty() {
+        return None;
+    }
+    bytes.iter().try_fold(0u32, |acc, byte| {
+        if byte.is_ascii_digit() {
+            acc.checked_mul(10)?.checked_add(u32::from(byte - b'0'))
+        }
+        else {
+            None
+        }
+    })
+}
+
+/**
+  Parses a hexadecimal number with an optional `0x` prefix
+*/
+pub fn parse_hex_u32(bytes: &[u8]) -> Option<u32> {
+    let digits = bytes.strip_prefix(b"0x").unwrap_or(bytes);
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0u32, |acc, byte| {
+        let digit = (*byte as char).to_digit(16)?;
+        acc.checked_mul(16)?.checked_add(digit)
+    })
+}
+
+pub fn parse_u8(bytes: &[u8]) -> Option<u8> {
+    parse_u32(bytes).and_then(|value| u8::try_from(value).ok())
+}
+
+pub fn parse_u16(bytes: &[u8]) -> Option<u16> {
+    parse_u32(bytes).and_then(|value| u16::try_from(value).ok())
+}
+
+pub fn parse_i8(bytes: &[u8]) -> Option<i8> {
+    match bytes.strip_prefix(b"-") {
+        Some(magnitude) => i8::try_from(-(parse_u8(magnitude)? as i16)).ok(),
+        None => i8::try_from(parse_u8(bytes)?).ok()
+    }
+}
+
+/**
+  Splits the arguments of a response line like `"ssid",-50,1` on commas
+  which are not inside quotes
+*/
+pub fn split_fields(line: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    line.split(move |byte| {
+        if escaped {
+            escaped = false;
+            return false;
+        }
+        match *byte {
+            b'\\' => escaped = true,
+            b'"' => in_quotes = !in_quotes,
+            b',' => return !in_quotes,
+            _ => {}
+        }
+        false
+    })
+}
+
+/**
+  Removes the surrounding quotes from `field` if there are any
+*/
+pub fn unquote(field: &[u8]) -> &[u8] {
+    match field {
+        [b'"', inner @ .., b'"'] => inner,
+        other => other
+    }
+}
+
+/**
+  IPv4 address of a host, printed as a dotted quad
+*/
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    /**
+      Returns true for `0.0.0.0`, which the device reports when it has no
+      address
+    */
+    pub fn is_unspecified(&self) -> bool {
+        self.0 == [0; 4]
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/**
+  Parses `field` as a dotted quad IPv4 address like `192.168.1.1`, optionally
+  surrounded by quotes. Whether addresses are quoted differs between firmware
+  versions, so every address in a response is parsed with this
+*/
+pub fn parse_ipv4(field: &[u8]) -> Option<Ipv4Address> {
+    let mut octets = [0; 4];
+    let mut parts = unquote(field).split(|byte| *byte == b'.');
+    for octet in octets.iter_mut() {
+        *octet = parse_u8(parts.next()?)?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Ipv4Address(octets))
+}
+
+/**
+  Content of the header of a `+IPD` message
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IpdHeader {
+    /// The connection the message arrived on if multiple connections are enabled
+    pub link_id: Option<u8>,
+    /// Length of the payload
+    pub len: usize,
+    /// Address and port of the sender if `AT+CIPDINFO=1` is set
+    pub remote: Option<(Ipv4Address, u16)>,
+}
+
+/**
+  Parses the header of a `+IPD` message. The leading `+IPD,` and the trailing
+  `:` are optional.
+
+  All forms of the header are supported, the form is detected from the
+  number of fields:
+
+  ```text
+  +IPD,<len>:
+  +IPD,<link id>,<len>:
+  +IPD,<len>,<remote ip>,<remote port>:
+  +IPD,<link id>,<len>,<remote ip>,<remote port>:
+  ```
+*/
+pub fn parse_ipd_header(buffer: &[u8]) -> Option<IpdHeader> {
+    let buffer = buffer.strip_prefix(b"+IPD,").unwrap_or(buffer);
+    let buffer = buffer.strip_suffix(b":").unwrap_or(buffer);
+
+    let mut fields: [&[u8]; 4] = [&[]; 4];
+    let mut field_count = 0;
+    for field in split_fields(buffer) {
+        *fields.get_mut(field_count)? = field;
+        field_count += 1;
+    }
+
+    let (link_id, len, remote) = match fields[..field_count] {
+        [len] => (None, len, None),
+        [link_id, len] => (Some(link_id), len, None),
+        [len, ip, port] => (None, len, Some((ip, port))),
+        [link_id, len, ip, port] => (Some(link_id), len, Some((ip, port))),
+        _ => return None
+    };
+
+    Some(IpdHeader {
+        link_id: match link_id {
+            Some(link_id) => Some(parse_u8(link_id)?),
+            None => None
+        },
+        len: parse_u32(len)? as usize,
+        remote: match remote {
+            Some((ip, port)) => {
+                let port = u16::try_from(parse_u32(port)?).ok()?;
+                Some((parse_ipv4(ip)?, port))
+            }
+            None => None
+        },
+    })
+}
+
+/**
+  Copies `bytes` into an `ArrayString`, truncating it if it doesn't fit.
+  Invalid UTF-8 is truncated as well
+*/
+pub fn truncated_string<A>(bytes: &[u8]) -> ArrayString<A>
+where A: Array<Item = u8> + Copy
+{
+    let mut result = ArrayString::new();
+    let bytes = &bytes[..bytes.len().min(result.capacity())];
+    let valid = match core::str::from_utf8(bytes) {
+        Ok(valid) => valid,
+        // Can not fail since the bytes up to valid_up_to are valid UTF-8
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap()
+    };
+    result.push_str(valid);
+    result
+}
+
+/**
+  Hardware address of a WiFi device
+*/
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MacAddress(pub [u8; 6]);
+
+/**
+  Parses `field` as a MAC address like `5c:cf:7f:12:34:56`, optionally
+  surrounded by quotes
+*/
+pub fn parse_mac(field: &[u8]) -> Option<MacAddress> {
+    let mut octets = [0; 6];
+    let mut parts = unquote(field).split(|byte| *byte == b':');
+    for octet in octets.iter_mut() {
+        let part = parts.next()?;
+        // from_str_radix would accept a sign like in `+f`
+        if part.len() != 2 || !part.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        *octet = u8::from_str_radix(core::str::from_utf8(part).ok()?, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(MacAddress(octets))
+}
+
+/**
+  Finds the reset cause in the boot banner of the device, for example
+  `ets Jan  8 2013,rst cause:5, boot mode:(3,6)`
+*/
+pub fn parse_reset_cause(boot_log: &[u8]) -> Option<u8> {
+    const MARKER: &[u8] = b"rst cause:";
+    let start = boot_log.windows(MARKER.len())
+        .position(|window| window == MARKER)? + MARKER.len();
+    let digits = boot_log[start..].iter()
+        .take_while(|byte| byte.is_ascii_digit())
+        .count();
+    parse_u8(&boot_log[start..start + digits])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipd_header_forms() {
+        assert_eq!(
+            parse_ipd_header(b"+IPD,5:"),
+            Some(IpdHeader { link_id: None, len: 5, remote: None })
+        );
+        assert_eq!(
+            parse_ipd_header(b"+IPD,2,1460:"),
+            Some(IpdHeader { link_id: Some(2), len: 1460, remote: None })
+        );
+        assert_eq!(
+            parse_ipd_header(b"+IPD,5,192.168.1.2,8080:"),
+            Some(IpdHeader {
+                link_id: None,
+                len: 5,
+                remote: Some((Ipv4Address([192, 168, 1, 2]), 8080))
+            })
+        );
+        assert_eq!(
+            parse_ipd_header(b"+IPD,0,5,\"10.0.0.1\",53:"),
+            Some(IpdHeader {
+                link_id: Some(0),
+                len: 5,
+                remote: Some((Ipv4Address([10, 0, 0, 1]), 53))
+            })
+        );
+    }
+
+    #[test]
+    fn ipd_header_without_prefix_and_colon() {
+        assert_eq!(
+            parse_ipd_header(b"1,7"),
+            Some(IpdHeader { link_id: Some(1), len: 7, remote: None })
+        );
+    }
+
+    #[test]
+    fn invalid_ipd_headers() {
+        // Without the colon the length runs into the payload
+        assert_eq!(parse_ipd_header(b"+IPD,5hello"), None);
+        assert_eq!(parse_ipd_header(b"+IPD,five:"), None);
+        assert_eq!(parse_ipd_header(b"+IPD,-5:"), None);
+        assert_eq!(parse_ipd_header(b"+IPD,99999999999:"), None);
+        assert_eq!(parse_ipd_header(b"+IPD,256,5:"), None);
+        assert_eq!(parse_ipd_header(b"+IPD,5,192.168.1.2,65536:"), None);
+        assert_eq!(parse_ipd_header(b"+IPD,0,5,192.168.1.2,80,1:"), None);
+        assert_eq!(parse_ipd_header(b"+IPD,:"), None);
+        assert_eq!(parse_ipd_header(b""), None);
+    }
+
+    #[test]
+    fn malformed_ipd_headers() {
+        assert_eq!(parse_ipd_header(b"+IPD"), None);
+        assert_eq!(parse_ipd_header(b"+IPD,1,2,3,4,5,6,7,8:"), None);
+        assert_eq!(parse_ipd_header(b"+IPD,5,192.168.1:"), None);
+        assert_eq!(parse_ipd_header(b"+IPD,\xff\x00:"), None);
+    }
+
+    #[test]
+    fn fields_are_split_outside_quotes() {
+        let mut fields = split_fields(b"\"a,\\\"b\",-5,,");
+        assert_eq!(fields.next(), Some(&b"\"a,\\\"b\""[..]));
+        assert_eq!(fields.next(), Some(&b"-5"[..]));
+        assert_eq!(fields.next(), Some(&b""[..]));
+        assert_eq!(fields.next(), Some(&b""[..]));
+        assert_eq!(fields.next(), None);
+        // An unterminated quote runs to the end of the line
+        assert_eq!(split_fields(b"\"a,b").count(), 1);
+    }
+
+    #[test]
+    fn malformed_macs() {
+        assert_eq!(parse_mac(b"5c:cf:7f:12:34"), None);
+        assert_eq!(parse_mac(b"5c:cf:7f:12:34:56:78"), None);
+        assert_eq!(parse_mac(b"5c:cf:7f:12:34:5"), None);
+        assert_eq!(parse_mac(b"5c:cf:7f:12:34:+f"), None);
+        assert_eq!(parse_mac(b"5c:cf:7f:12:34:zz"), None);
+        assert_eq!(parse_mac(b"\"5c:cf:7f:12:34:56"), None);
+        assert_eq!(parse_mac(b"\xff\xfe"), None);
+        assert_eq!(parse_mac(b""), None);
+        assert_eq!(
+            parse_mac(b"\"5c:cf:7f:12:34:56\""),
+            Some(MacAddress([0x5c, 0xcf, 0x7f, 0x12, 0x34, 0x56]))
+        );
+    }
+
+    #[test]
+    fn ipv4_addresses_with_and_without_quotes() {
+        assert_eq!(parse_ipv4(b"192.168.1.2"), Some(Ipv4Address([192, 168, 1, 2])));
+        assert_eq!(parse_ipv4(b"\"192.168.1.2\""), Some(Ipv4Address([192, 168, 1, 2])));
+        assert_eq!(parse_ipv4(b"0.0.0.0"), Some(Ipv4Address([0, 0, 0, 0])));
+        assert_eq!(parse_ipv4(b"\"255.255.255.255\""), Some(Ipv4Address([255, 255, 255, 255])));
+        // Only matching quotes are removed
+        assert_eq!(parse_ipv4(b"\"192.168.1.2"), None);
+        assert_eq!(parse_ipv4(b"192.168.1.2\""), None);
+    }
+
+    #[test]
+    fn ipv4_address_display() {
+        use core::fmt::Write;
+        let mut text = ArrayString::<[u8; 15]>::new();
+        write!(text, "{}", Ipv4Address([192, 168, 1, 255])).unwrap();
+        assert_eq!(&text, "192.168.1.255");
+        assert!(Ipv4Address([0; 4]).is_unspecified());
+    }
+
+    #[test]
+    fn ipv4_octets_out_of_range() {
+        assert_eq!(parse_ipv4(b"256.0.0.1"), None);
+        assert_eq!(parse_ipv4(b"\"192.168.1.256\""), None);
+        assert_eq!(parse_ipv4(b"192.168.-1.1"), None);
+        assert_eq!(parse_ipv4(b"192.168.1.4294967296"), None);
+    }
+
+    #[test]
+    fn malformed_ipv4_addresses() {
+        assert_eq!(parse_ipv4(b"192.168.1"), None);
+        assert_eq!(parse_ipv4(b"192.168.1.1.1"), None);
+        assert_eq!(parse_ipv4(b"192.168..1"), None);
+        assert_eq!(parse_ipv4(b"192.168.1.x"), None);
+        assert_eq!(parse_ipv4(b"1111111111111.0.0.1"), None);
+        assert_eq!(parse_ipv4(b"\xff.\x00.1.1"), None);
+        assert_eq!(parse_ipv4(b""), None);
+    }
+}
@@ -0,0 +1,38 @@
+use embedded_hal as hal;
+
+use core::marker::PhantomData;
+use core::convert::Infallible;
+
+/**
+  Placeholder for an optional pin which is not connected.
+
+  `E` is the error type the pin pretends to have, which allows it to stand
+  in for pins that are required to share an error type with another pin.
+  Setting the level of a `NoPin` does nothing
+*/
+pub struct NoPin<E = Infallible> {
+    _error: PhantomData<E>
+}
+
+impl<E> NoPin<E> {
+    pub fn new() -> Self {
+        Self { _error: PhantomData }
+    }
+}
+
+impl<E> Default for NoPin<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E> hal::digital::v2::OutputPin for NoPin<E> {
+    type Error = E;
+
+    fn set_low(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+    fn set_high(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+}
@@ -49,12 +49,24 @@ where
 }
 
 /**
+  Reads bytes into `buffer`, treating it as a circular buffer, until `parser`
+  returns a value or no byte is received within `timeout`
+
+  Line terminators received before anything else are skipped since some
+  firmware sends an empty line before its responses. This leaves the whole
+  buffer for the message itself.
+
+  `received` is incremented for every byte stored which allows the caller to
+  inspect the buffer after a timeout. Reading continues where the previous
+  call with the same `buffer` and `received` stopped, so a message which was
+  only partially received before a timeout can still be matched
 */
 pub fn read_until_message<S, T, C, R>(
     rx: &mut S,
     timer: &mut T,
     timeout: Millisecond,
     buffer: &mut [u8],
+    received: &mut usize,
     parser: &C
 ) -> Result<R, Error<S::Error>>
 where
@@ -62,12 +74,14 @@ where
     S: hal::serial::Read<u8>,
     C: Fn(&[u8], usize) -> Option<R>,
 {
-    let mut ptr = 0;
+    let mut ptr = *received % buffer.len();
     loop {
         match read_with_timeout(rx, timer, timeout) {
+            Ok(b'\r') | Ok(b'\n') if *received == 0 => {},
             Ok(byte) => {
                 buffer[ptr] = byte;
                 ptr = (ptr+1) % buffer.len();
+                *received += 1;
 
                 if let Some(val) = parser(buffer, ptr) {
                     return Ok(val);
@@ -85,6 +99,37 @@ where
     }
 }
 
+/**
+  Reads a line terminated by `\n` into `buffer` and returns the length of the
+  line without the line terminator. Bytes which do not fit in `buffer` are
+  discarded
+*/
+pub fn read_line<S, T>(
+    rx: &mut S,
+    timer: &mut T,
+    timeout: Millisecond,
+    buffer: &mut [u8]
+) -> Result<usize, Error<S::Error>>
+where
+    T: LongTimer,
+    S: hal::serial::Read<u8>,
+{
+    let mut length = 0;
+    loop {
+        let byte = read_with_timeout(rx, timer, timeout)?;
+        if byte == b'\n' {
+            if length > 0 && buffer[length - 1] == b'\r' {
+                length -= 1;
+            }
+            return Ok(length);
+        }
+        if length < buffer.len() {
+            buffer[length] = byte;
+            length += 1;
+        }
+    }
+}
+
 pub fn write_all<S>(serial: &mut S, buffer: &[u8]) -> Result<(), S::Error>
 where
     S: hal::serial::Write<u8>
@@ -96,3 +141,91 @@ where
     Ok(())
 }
 
+
+/**
+  Writes `buffer` to `serial`, failing with `Error::TimedOut` if `timer`
+  runs out before all bytes have been written. The timer must already
+  be started
+*/
+pub fn write_all_until_timeout<S, T>(
+    serial: &mut S,
+    timer: &mut T,
+    buffer: &[u8]
+) -> Result<(), Error<S::Error>>
+where
+    T: LongTimer,
+    S: hal::serial::Write<u8>
+{
+    for &byte in buffer {
+        loop {
+            if timer.wait().is_ok() {
+                return Err(Error::TimedOut);
+            }
+            match serial.write(byte) {
+                Ok(()) => break,
+                Err(nb::Error::WouldBlock) => continue,
+                Err(nb::Error::Other(e)) => return Err(Error::Serial(e))
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::compare_circular_buffer;
+    use crate::testing::{ScriptedRx, TestTimer, GAP};
+
+    fn ok(buffer: &[u8], offset: usize) -> Option<()> {
+        if compare_circular_buffer(buffer, offset, b"OK\r\n") { Some(()) } else { None }
+    }
+
+    fn read(rx: &mut ScriptedRx, timeout: u32, buffer: &mut [u8], received: &mut usize)
+        -> Result<(), Error<core::convert::Infallible>>
+    {
+        read_until_message(rx, &mut TestTimer::new(), Millisecond(timeout), buffer, received, &ok)
+    }
+
+    #[test]
+    fn leading_line_breaks_are_skipped() {
+        // Only fits the response itself
+        let mut buffer = [0; 4];
+        let mut received = 0;
+        let mut rx = ScriptedRx::new(&[b"\r\n\r\nOK\r\n"], GAP);
+        assert!(read(&mut rx, 100, &mut buffer, &mut received).is_ok());
+        assert_eq!(received, 4);
+    }
+
+    #[test]
+    fn responses_split_across_reads_are_matched() {
+        let mut buffer = [0; 16];
+        let mut received = 0;
+        let mut rx = ScriptedRx::new(&[b"\r\n", b"O", b"K\r", b"\n"], GAP);
+        assert!(read(&mut rx, 100, &mut buffer, &mut received).is_ok());
+    }
+
+    #[test]
+    fn reading_continues_after_a_timeout() {
+        let mut buffer = [0; 16];
+        let mut received = 0;
+        let mut rx = ScriptedRx::new(&[b"\r\nO", b"K\r\n"], GAP);
+        match read(&mut rx, GAP / 2, &mut buffer, &mut received) {
+            Err(Error::TimedOut) => {}
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(received, 1);
+        assert!(read(&mut rx, 100, &mut buffer, &mut received).is_ok());
+        assert_eq!(received, 4);
+    }
+
+    #[test]
+    fn responses_are_matched_after_the_buffer_wrapped() {
+        let mut buffer = [0; 4];
+        let mut received = 0;
+        let mut rx = ScriptedRx::new(&[b"\r\n+CIPMUX:1\r\n\r\nOK\r\n"], GAP);
+        assert!(read(&mut rx, 100, &mut buffer, &mut received).is_ok());
+        assert_eq!(received, 17);
+    }
+}
@@ -0,0 +1,145 @@
+use core::fmt;
+
+use embedded_hal as hal;
+
+use crate::{Esp8266, Error, LongTimer, serial};
+use crate::parse::{parse_u8, parse_u16};
+
+/// Timezones supported by `AT+CIPSNTPCFG`, in hours from UTC
+const TIMEZONES: core::ops::RangeInclusive<i8> = -11..=13;
+
+const MONTHS: [&[u8]; 12] = [
+    b"Jan", b"Feb", b"Mar", b"Apr", b"May", b"Jun",
+    b"Jul", b"Aug", b"Sep", b"Oct", b"Nov", b"Dec",
+];
+
+/**
+  A point in time in the timezone set with `Esp8266::configure_sntp`,
+  returned by `Esp8266::get_time`.
+
+  Formatting it with `{}` gives the ISO 8601 form, `2016-08-04T14:48:05`,
+  which is convenient for stamping messages
+*/
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct Timestamp {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl Timestamp {
+    /**
+      Parses the `asctime` format used by the device, like
+      `Thu Aug 04 14:48:05 2016`
+    */
+    pub fn parse(text: &[u8]) -> Option<Self> {
+        let mut fields = text.split(|byte| *byte == b' ').filter(|field| !field.is_empty());
+        // The day of the week follows from the date
+        fields.next()?;
+        let month_name = fields.next()?;
+        let month = MONTHS.iter().position(|name| *name == month_name)? as u8 + 1;
+        let day = parse_u8(fields.next()?).filter(|day| (1..=31).contains(day))?;
+        let mut time = fields.next()?.split(|byte| *byte == b':');
+        let hour = parse_u8(time.next()?).filter(|hour| *hour < 24)?;
+        let minute = parse_u8(time.next()?).filter(|minute| *minute < 60)?;
+        // 60 is a leap second
+        let second = parse_u8(time.next()?).filter(|second| *second <= 60)?;
+        let year = parse_u16(fields.next()?)?;
+        if time.next().is_some() || fields.next().is_some() {
+            return None;
+        }
+        Some(Self { year, month, day, hour, minute, second })
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+impl<Tx, Rx, Timer, Rst, HwRst, Led> Esp8266<Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    /**
+      Makes the device keep its clock in sync with `server` using SNTP.
+      `timezone` is the offset from UTC in hours, -11 to 13, which is applied
+      to the times returned by `get_time`
+    */
+    pub fn configure_sntp(&mut self, timezone: i8, server: &str) -> return_type!(()) {
+        if !TIMEZONES.contains(&timezone) {
+            return Err(Error::InvalidArgument("timezone"));
+        }
+        if server.is_empty() {
+            return Err(Error::InvalidArgument("server"));
+        }
+        self.begin_command(b"+CIPSNTPCFG=1,")?;
+        if timezone < 0 {
+            self.send_raw(b"-")?;
+        }
+        self.send_number(u32::from(timezone.unsigned_abs()))?;
+        self.send_raw(b",\"")?;
+        self.send_escaped(server.as_bytes())?;
+        self.send_raw(b"\"")?;
+        self.end_command()?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Reads the current time from the device with `AT+CIPSNTPTIME?`.
+
+      Until the clock has been synchronised after `configure_sntp`, the
+      device reports a time in 1970
+    */
+    pub fn get_time(&mut self) -> return_type!(Timestamp) {
+        self.query_value("+CIPSNTPTIME?", b"+CIPSNTPTIME:", Timestamp::parse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_asctime() {
+        assert_eq!(
+            Timestamp::parse(b"Thu Aug 04 14:48:05 2016"),
+            Some(Timestamp { year: 2016, month: 8, day: 4, hour: 14, minute: 48, second: 5 })
+        );
+        // Days before the 10th are padded with a space rather than a zero
+        assert_eq!(
+            Timestamp::parse(b"Thu Jan  1 00:00:00 1970"),
+            Some(Timestamp { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 })
+        );
+    }
+
+    #[test]
+    fn malformed_timestamps() {
+        assert_eq!(Timestamp::parse(b"Thu Aug 04 14:48:05"), None);
+        assert_eq!(Timestamp::parse(b"Thu Aug 04 14:48 2016"), None);
+        assert_eq!(Timestamp::parse(b"Thu Aug 04 14:48:05:01 2016"), None);
+        assert_eq!(Timestamp::parse(b"Thu Aug 04 14:48:05 2016 extra"), None);
+        assert_eq!(Timestamp::parse(b"Thu Foo 04 14:48:05 2016"), None);
+        assert_eq!(Timestamp::parse(b"Thu Aug 32 14:48:05 2016"), None);
+        assert_eq!(Timestamp::parse(b"Thu Aug 04 24:48:05 2016"), None);
+        assert_eq!(Timestamp::parse(b"Thu Aug 04 14:60:05 2016"), None);
+        assert_eq!(Timestamp::parse(b"Thu Aug 04 14:48:61 2016"), None);
+        assert_eq!(Timestamp::parse(b"Thu Aug 04 14:48:05 99999"), None);
+        assert_eq!(Timestamp::parse(b"\xff\x00 \xfe"), None);
+        assert_eq!(Timestamp::parse(b""), None);
+    }
+}
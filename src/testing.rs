@@ -0,0 +1,137 @@
+//! Scripted serial port and timer for exercising the driver in tests
+
+use embedded_hal as hal;
+
+use core::convert::Infallible;
+
+use arrayvec::ArrayVec;
+
+use crate::{serial, Error, Esp8266, LongTimer, Millisecond, NoPin};
+
+/// Number of reads which return `WouldBlock` between two scripted chunks
+pub const GAP: u32 = 10;
+
+/// What the device sends while starting up, and in response to `ATE0`
+pub const STARTUP: [&[u8]; 2] = [b"\r\nready\r\nWIFI CONNECTED\r\nWIFI GOT IP\r\n", b"\r\nOK\r\n"];
+
+pub type TestError = Error<serial::Error<Infallible>, Infallible, Infallible>;
+pub type TestEsp<'a> = Esp8266<RecordingTx, ScriptedRx<'a>, TestTimer, NoPin>;
+
+/**
+  Serial receiver which returns `chunks` one after another, blocking for
+  `gap` reads between them like a device that sends its responses in bursts
+*/
+pub struct ScriptedRx<'a> {
+    chunks: ArrayVec<[&'a [u8]; 32]>,
+    chunk: usize,
+    position: usize,
+    gap: u32,
+    waited: u32,
+}
+
+impl<'a> ScriptedRx<'a> {
+    pub fn new(chunks: &[&'a [u8]], gap: u32) -> Self {
+        Self {
+            chunks: chunks.iter().cloned().collect(),
+            chunk: 0,
+            position: 0,
+            gap,
+            waited: 0,
+        }
+    }
+}
+
+impl<'a> hal::serial::Read<u8> for ScriptedRx<'a> {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        let chunk = self.chunks.get(self.chunk).ok_or(nb::Error::WouldBlock)?;
+        if let Some(&byte) = chunk.get(self.position) {
+            self.position += 1;
+            return Ok(byte);
+        }
+        if self.waited < self.gap {
+            self.waited += 1;
+            return Err(nb::Error::WouldBlock);
+        }
+        self.chunk += 1;
+        self.position = 0;
+        self.waited = 0;
+        self.read()
+    }
+}
+
+/**
+  Serial transmitter which keeps everything written to it
+*/
+pub struct RecordingTx {
+    pub written: ArrayVec<[u8; 4096]>,
+}
+
+impl hal::serial::Write<u8> for RecordingTx {
+    type Error = Infallible;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        self.written.push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/**
+  Timer which runs out after being polled as many times as the number of
+  milliseconds it was started with
+*/
+pub struct TestTimer {
+    remaining: u32,
+}
+
+impl TestTimer {
+    pub fn new() -> Self {
+        Self { remaining: 0 }
+    }
+}
+
+impl LongTimer for TestTimer {
+    fn wait(&mut self) -> nb::Result<(), Infallible> {
+        match self.remaining {
+            0 => Ok(()),
+            _ => {
+                self.remaining -= 1;
+                Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+
+    fn start(&mut self, Millisecond(duration): Millisecond) {
+        self.remaining = duration;
+    }
+}
+
+/**
+  Returns a driver which has gone through start up and then receives
+  `responses`. Nothing written during start up is kept in `tx.written`
+*/
+pub fn started<'a>(responses: &[&'a [u8]]) -> TestEsp<'a> {
+    let mut chunks = ArrayVec::<[&'a [u8]; 32]>::new();
+    chunks.extend(STARTUP.iter().cloned());
+    chunks.extend(responses.iter().cloned());
+    let mut esp = Esp8266::new(
+        RecordingTx { written: ArrayVec::new() },
+        ScriptedRx::new(&chunks, GAP),
+        TestTimer::new(),
+        NoPin::new()
+    ).unwrap_or_else(|_| panic!("start up failed"));
+    esp.tx.written.clear();
+    esp
+}
+
+/**
+  Returns true if `needle` was written anywhere in `haystack`
+*/
+pub fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
@@ -1,5 +1,5 @@
-use nb;
 use core::convert::Infallible;
+use core::ops::{Add, Sub};
 
 /**
     A countdown timer which nonblockingly waits until the specified countdown
@@ -12,7 +12,7 @@ use core::convert::Infallible;
     here is a sample impl for the stm32f1xx_hal
 
 
-    ```rust
+    ```rust,ignore
     struct LongTimer<T> {
         timer: Timer<T>,
         milliseconds_remaining: u32,
@@ -79,17 +79,157 @@ pub trait LongTimer {
         requirements are very low.
     */
     fn start(&mut self, duration: Millisecond);
+
+    /**
+        Returns the time passed since some fixed point, like when the timer was
+        created, wrapping around on overflow. Unlike the countdown, this must
+        not be affected by `start`. Use `Millisecond::wrapping_sub` for the
+        time between two values.
+
+        Only used for measuring command latency, timers which can't tell the
+        time can keep the default which returns `None`
+    */
+    fn elapsed(&self) -> Option<Millisecond> {
+        None
+    }
 }
 
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Second(pub u32);
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Millisecond(pub u32);
 
+impl Millisecond {
+    pub const fn new(duration: u32) -> Self {
+        Millisecond(duration)
+    }
+
+    /**
+      Creates a duration of `seconds` seconds, saturating like the conversion
+      from `Second`
+    */
+    pub const fn from_secs(seconds: u32) -> Self {
+        Millisecond(seconds.saturating_mul(1000))
+    }
+
+    pub const fn as_millis(self) -> u32 {
+        self.0
+    }
+
+    /**
+      Subtracts `other` from the duration, returning zero instead of
+      overflowing if `other` is longer
+    */
+    pub const fn saturating_sub(self, other: Millisecond) -> Self {
+        Millisecond(self.0.saturating_sub(other.0))
+    }
+
+    /**
+      Subtracts `other` from the duration, wrapping around on overflow. This
+      gives the time between two `LongTimer::elapsed` values even if the
+      counter wrapped in between
+    */
+    pub const fn wrapping_sub(self, other: Millisecond) -> Self {
+        Millisecond(self.0.wrapping_sub(other.0))
+    }
+}
+
+/// Saturates at the longest representable duration instead of overflowing
+impl Add for Millisecond {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Millisecond(self.0.saturating_add(other.0))
+    }
+}
+
+/// Saturates at zero instead of overflowing, see `wrapping_sub` for the time
+/// between two `LongTimer::elapsed` values
+impl Sub for Millisecond {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Millisecond(self.0.saturating_sub(other.0))
+    }
+}
+
+impl Second {
+    pub const fn new(duration: u32) -> Self {
+        Second(duration)
+    }
+
+    /**
+      Converts the duration to milliseconds, returning `None` if it is longer
+      than the longest representable `Millisecond` (about 49.7 days)
+    */
+    pub fn checked_to_millisecond(self) -> Option<Millisecond> {
+        self.0.checked_mul(1000).map(Millisecond)
+    }
+}
+
+/**
+  Durations longer than `u32::MAX` milliseconds (about 49.7 days) saturate
+  at `u32::MAX` milliseconds
+*/
 impl From<Second> for Millisecond {
     fn from(Second(duration): Second) -> Self {
-        return Millisecond(duration * 1000);
+        Millisecond(duration.saturating_mul(1000))
+    }
+}
+
+
+/**
+  Timeouts used when communicating with the device. Set with
+  `Esp8266::set_timeouts`
+*/
+#[derive(Clone, Copy)]
+pub struct TimeoutConfig {
+    /// Time to wait for the device to start and connect to WiFi
+    pub startup: Millisecond,
+    /// Time to wait for the response to a command
+    pub command: Millisecond,
+    /// Time to wait for the device to join an access point
+    pub join: Millisecond,
+    /// Time allowed for sending the payload of a message once the device
+    /// is ready to receive it
+    pub payload: Millisecond,
+    /// Time to wait after closing a connection before opening the next one.
+    /// Some firmware needs this to finish tearing down the old connection
+    pub post_close_delay: Millisecond,
+    /// Time to wait for `SEND OK` after sending over a UDP connection. For
+    /// UDP it only means that the message was queued, so not receiving it
+    /// within this time is not treated as an error
+    pub udp_ack: Millisecond,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            startup: Second(10).into(),
+            command: Second(5).into(),
+            join: Second(20).into(),
+            payload: Second(5).into(),
+            post_close_delay: Millisecond(0),
+            udp_ack: Second(1).into(),
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic_saturates() {
+        assert_eq!(Millisecond(u32::MAX) + Millisecond(2), Millisecond(u32::MAX));
+        assert_eq!(Millisecond(1) - Millisecond(u32::MAX), Millisecond(0));
+        assert_eq!(Millisecond(5) - Millisecond(3), Millisecond(2));
+    }
+
+    #[test]
+    fn wrapping_sub_measures_across_counter_wrap() {
+        assert_eq!(Millisecond(1).wrapping_sub(Millisecond(u32::MAX)), Millisecond(2));
+        assert_eq!(Millisecond(5).wrapping_sub(Millisecond(3)), Millisecond(2));
+    }
+}
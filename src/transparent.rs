@@ -0,0 +1,385 @@
+use embedded_hal as hal;
+
+use nb::block;
+
+use arrayvec::ArrayVec;
+
+use crate::{ConnectionType, Esp8266, Error, LongTimer, Millisecond, MuxMode, Second, serial};
+use crate::parse::parse_u8;
+use crate::observer::ObservedRx;
+
+/**
+  Time without data which separates two messages received in transparent mode
+*/
+const MESSAGE_GAP: Millisecond = Millisecond(20);
+
+/**
+  Silence required before and after the `+++` sequence which exits
+  transparent mode
+*/
+const ESCAPE_GUARD_TIME: Second = Second(1);
+
+/// Longest idle time in seconds after which `AT+CIPSTO` can close connections
+pub(crate) const MAX_IDLE_TIMEOUT: u16 = 7200;
+
+/**
+  Lines the device sends in transparent mode when the connection drops or
+  is re-established
+*/
+const NOTIFICATIONS: [(&[u8], TransparentEvent); 2] = [
+    (b"CLOSED\r\n", TransparentEvent::Closed),
+    (b"CONNECT\r\n", TransparentEvent::Connected),
+];
+
+/**
+  Length of the longest of the `NOTIFICATIONS`
+*/
+const NOTIFICATION_LENGTH: usize = 9;
+
+/**
+  What the start of a line received in transparent mode turned out to be
+*/
+#[derive(Debug, PartialEq)]
+enum LineStart {
+    /// One of the `NOTIFICATIONS`, which isn't part of the data
+    Notification(TransparentEvent),
+    /// Could still become a notification with more bytes
+    Partial,
+    Data,
+}
+
+fn classify_line_start(line: &[u8]) -> LineStart {
+    for (notification, event) in NOTIFICATIONS.iter() {
+        if line == *notification {
+            return LineStart::Notification(*event);
+        }
+        if notification.starts_with(line) {
+            return LineStart::Partial;
+        }
+    }
+    LineStart::Data
+}
+
+/**
+  Stores `byte` at `length` in `buffer` if it fits
+*/
+fn store(buffer: &mut [u8], length: &mut usize, byte: u8) {
+    if let Some(target) = buffer.get_mut(*length) {
+        *target = byte;
+        *length += 1;
+    }
+}
+
+/**
+  Something which happened on the connection while in transparent mode
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransparentEvent {
+    /// Data was received and stored in the buffer, contains the length
+    Data(usize),
+    /// The connection was lost
+    Closed,
+    /// The connection was re-established by the device
+    Connected,
+}
+
+/**
+  How data is sent over a connection, reported by `Esp8266::query_mode`
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransferMode {
+    /// Messages are sent with `CIPSEND` and received as `+IPD`
+    Normal,
+    /// Transparent transmission is enabled with `AT+CIPMODE=1`
+    Transparent,
+}
+
+/**
+  What the device does with idle server connections, set by
+  `Esp8266::set_reconnect_policy`
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReconnectPolicy {
+    /// Keep idle connections open
+    KeepOpen,
+    /// Close connections which have been idle for the specified number of
+    /// seconds, 1 to 7200, forcing the remote end to reconnect. Other values
+    /// are rejected with `Error::InvalidArgument`
+    CloseIdleAfter(u16),
+}
+
+impl<Tx, Rx, Timer, Rst, HwRst, Led> Esp8266<Tx, Rx, Timer, Rst, HwRst, Led>
+where Tx: hal::serial::Write<u8>,
+      Rx: hal::serial::Read<u8>,
+      Timer: LongTimer,
+      Rst: hal::digital::v2::OutputPin,
+      HwRst: hal::digital::v2::OutputPin<Error = Rst::Error>,
+      Led: hal::digital::v2::OutputPin<Error = Rst::Error>
+{
+    /**
+      Enters transparent mode on the currently open connection. Everything
+      written with `transparent_write` is then sent directly over the
+      connection and received data is read with `poll_transparent`, without
+      any `CIPSEND` or `+IPD` framing.
+
+      Requires single connection mode. Normal commands can not be used until
+      `exit_transparent_mode` is called
+    */
+    pub fn enter_transparent_mode(&mut self) -> return_type!(()) {
+        self.require_mux_mode(MuxMode::Single)?;
+        self.send_at_command("+CIPMODE=1")?;
+        self.wait_for_ok(self.state.timeouts.command)?;
+        self.send_at_command("+CIPSEND")?;
+        self.wait_for_prompt(self.state.timeouts.command)?;
+        self.state.transparent = true;
+        self.state.transparent_event = None;
+        Ok(())
+    }
+
+    /**
+      Leaves transparent mode by sending `+++` surrounded by the required
+      pauses. The connection stays open.
+
+      Fails with `Error::NotInTransparentMode` outside of transparent mode,
+      where the device would not treat `+++` as an escape sequence
+    */
+    pub fn exit_transparent_mode(&mut self) -> return_type!(()) {
+        if !self.state.transparent {
+            return Err(Error::NotInTransparentMode);
+        }
+        self.timer.start(ESCAPE_GUARD_TIME.into());
+        block!(self.timer.wait()).unwrap();
+        self.write_raw(b"+++")?;
+        self.timer.start(ESCAPE_GUARD_TIME.into());
+        block!(self.timer.wait()).unwrap();
+        self.state.transparent = false;
+
+        self.send_at_command("+CIPMODE=0")?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+
+    /**
+      Reads the transfer mode set with `AT+CIPMODE` from the device.
+
+      Since the device answered a command it is not passing data through, so
+      the local state is updated to no longer be in transparent mode
+    */
+    pub fn query_mode(&mut self) -> return_type!(TransferMode) {
+        let mode = self.query_value("+CIPMODE?", b"+CIPMODE:", |line| {
+            match parse_u8(line)? {
+                0 => Some(TransferMode::Normal),
+                1 => Some(TransferMode::Transparent),
+                _ => None
+            }
+        })?;
+        self.state.transparent = false;
+        Ok(mode)
+    }
+
+    /**
+      Sends `data` over the connection while in transparent mode.
+
+      Other commands fail with `Error::InTransparentMode` until
+      `exit_transparent_mode` is called, and this fails with
+      `Error::NotInTransparentMode` outside of transparent mode, where the
+      data would be interpreted as commands
+    */
+    pub fn transparent_write(&mut self, data: &[u8]) -> return_type!(()) {
+        if !self.state.transparent {
+            return Err(Error::NotInTransparentMode);
+        }
+        self.write_raw(data)
+    }
+
+    /**
+      Waits up to `timeout` for something to happen on the connection while
+      in transparent mode. Received data is stored in `buffer`, bytes which
+      don't fit are discarded. Returns `None` if nothing happened.
+
+      The `CLOSED` and `CONNECT` notifications which the device sends when
+      the connection drops or is re-established are removed from the data
+      and reported as events. Reading stops at the first notification, if
+      data was received before it, the data is returned first and the event
+      by the next call. Anything after the notification is left for the
+      next call
+    */
+    pub fn poll_transparent(&mut self, buffer: &mut [u8], timeout: Millisecond)
+        -> return_type!(Option<TransparentEvent>)
+    {
+        if !self.state.transparent {
+            return Err(Error::NotInTransparentMode);
+        }
+        if let Some(event) = self.state.transparent_event.take() {
+            return Ok(Some(event));
+        }
+        if self.state.dry_run {
+            return Ok(None);
+        }
+        let mut length = 0;
+        // The start of the current line while it may still be a notification
+        let mut line = ArrayVec::<[u8; NOTIFICATION_LENGTH]>::new();
+        let mut line_start = true;
+        let mut gap = timeout;
+        let notification = loop {
+            let byte = match serial::read_with_timeout(
+                &mut ObservedRx::new(&mut self.rx, self.state.observer, &mut self.state.stats, &mut self.state.error_codes),
+                &mut self.timer,
+                gap
+            ) {
+                Ok(byte) => byte,
+                Err(serial::Error::TimedOut) => break None,
+                Err(e) => return Err(Error::RxError(e))
+            };
+            gap = MESSAGE_GAP;
+            if !line_start {
+                // Notifications are detected even once the buffer is full
+                store(buffer, &mut length, byte);
+                line_start = byte == b'\n';
+                continue;
+            }
+            // Can not fail since a line which is as long as the longest
+            // notification is never partial
+            line.push(byte);
+            match classify_line_start(&line) {
+                LineStart::Notification(event) => break Some(event),
+                LineStart::Partial => {}
+                LineStart::Data => {
+                    for &byte in line.iter() {
+                        store(buffer, &mut length, byte);
+                    }
+                    line_start = byte == b'\n';
+                    line.clear();
+                }
+            }
+        };
+
+        let event = match notification {
+            Some(event) => event,
+            None => {
+                // The data ended with what looked like the start of a
+                // notification
+                for &byte in line.iter() {
+                    store(buffer, &mut length, byte);
+                }
+                return Ok(if length == 0 { None } else { Some(TransparentEvent::Data(length)) });
+            }
+        };
+        match event {
+            TransparentEvent::Closed => self.connection_closed(),
+            // The device only reconnects TCP connections by itself
+            _ => {
+                self.set_status_led(true);
+                self.state.connection_type = Some(ConnectionType::Tcp);
+            }
+        }
+        if length == 0 {
+            return Ok(Some(event));
+        }
+        self.state.transparent_event = Some(event);
+        Ok(Some(TransparentEvent::Data(length)))
+    }
+
+    /**
+      Sets how the device treats idle server connections using `AT+CIPSTO`.
+      Closing idle connections makes bridges reconnect instead of keeping
+      a stale connection around
+    */
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) -> return_type!(()) {
+        let seconds = match policy {
+            ReconnectPolicy::KeepOpen => 0,
+            ReconnectPolicy::CloseIdleAfter(seconds @ 1..=MAX_IDLE_TIMEOUT) => seconds,
+            ReconnectPolicy::CloseIdleAfter(_) => {
+                return Err(Error::InvalidArgument("policy"))
+            }
+        };
+        self.begin_command(b"+CIPSTO=")?;
+        self.send_number(u32::from(seconds))?;
+        self.end_command()?;
+        self.wait_for_ok(self.state.timeouts.command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{started, TestEsp};
+
+    const TIMEOUT: Millisecond = Millisecond(100);
+
+    fn transparent<'a>(chunks: &[&'a [u8]]) -> TestEsp<'a> {
+        let mut esp = started(chunks);
+        esp.state.transparent = true;
+        esp
+    }
+
+    #[test]
+    fn notification_lines_are_classified() {
+        assert_eq!(classify_line_start(b"CLOSED\r\n"), LineStart::Notification(TransparentEvent::Closed));
+        assert_eq!(classify_line_start(b"CONNECT\r\n"), LineStart::Notification(TransparentEvent::Connected));
+        assert_eq!(classify_line_start(b"C"), LineStart::Partial);
+        assert_eq!(classify_line_start(b"CONN"), LineStart::Partial);
+        assert_eq!(classify_line_start(b"CLOSED\r"), LineStart::Partial);
+        assert_eq!(classify_line_start(b"CLOSEX"), LineStart::Data);
+        assert_eq!(classify_line_start(b"x"), LineStart::Data);
+    }
+
+    #[test]
+    fn exit_outside_of_transparent_mode_is_an_error() {
+        let mut esp = started(&[]);
+        assert!(matches!(esp.exit_transparent_mode(), Err(Error::NotInTransparentMode)));
+        assert!(esp.tx.written.is_empty());
+    }
+
+    #[test]
+    fn idle_timeouts_out_of_range_are_rejected() {
+        let mut esp = started(&[b"\r\nOK\r\n"]);
+        assert!(matches!(
+            esp.set_reconnect_policy(ReconnectPolicy::CloseIdleAfter(0)),
+            Err(Error::InvalidArgument("policy"))
+        ));
+        assert!(matches!(
+            esp.set_reconnect_policy(ReconnectPolicy::CloseIdleAfter(MAX_IDLE_TIMEOUT + 1)),
+            Err(Error::InvalidArgument("policy"))
+        ));
+        assert!(matches!(esp.set_server_timeout(MAX_IDLE_TIMEOUT + 1), Err(Error::InvalidArgument("seconds"))));
+        assert!(esp.tx.written.is_empty());
+        esp.set_server_timeout(MAX_IDLE_TIMEOUT).unwrap();
+        assert_eq!(&esp.tx.written[..], b"AT+CIPSTO=7200\r\n");
+    }
+
+    #[test]
+    fn back_to_back_notifications_are_reported_one_at_a_time() {
+        let mut esp = transparent(&[b"CLOSED\r\nCONNECT\r\n"]);
+        let mut buffer = [0; 16];
+        assert_eq!(esp.poll_transparent(&mut buffer, TIMEOUT).unwrap(), Some(TransparentEvent::Closed));
+        assert_eq!(esp.poll_transparent(&mut buffer, TIMEOUT).unwrap(), Some(TransparentEvent::Connected));
+        assert_eq!(esp.poll_transparent(&mut buffer, TIMEOUT).unwrap(), None);
+    }
+
+    #[test]
+    fn data_around_a_notification_is_not_merged() {
+        let mut esp = transparent(&[b"abc\r\nCLOSED\r\nxyz"]);
+        let mut buffer = [0; 16];
+        assert_eq!(esp.poll_transparent(&mut buffer, TIMEOUT).unwrap(), Some(TransparentEvent::Data(5)));
+        assert_eq!(&buffer[..5], b"abc\r\n");
+        assert_eq!(esp.poll_transparent(&mut buffer, TIMEOUT).unwrap(), Some(TransparentEvent::Closed));
+        assert_eq!(esp.poll_transparent(&mut buffer, TIMEOUT).unwrap(), Some(TransparentEvent::Data(3)));
+        assert_eq!(&buffer[..3], b"xyz");
+    }
+
+    #[test]
+    fn notifications_after_a_full_buffer_are_found() {
+        let mut esp = transparent(&[b"abcdefgh\r\nCLOSED\r\n"]);
+        let mut buffer = [0; 4];
+        assert_eq!(esp.poll_transparent(&mut buffer, TIMEOUT).unwrap(), Some(TransparentEvent::Data(4)));
+        assert_eq!(esp.poll_transparent(&mut buffer, TIMEOUT).unwrap(), Some(TransparentEvent::Closed));
+    }
+
+    #[test]
+    fn notifications_inside_lines_are_data() {
+        let mut esp = transparent(&[b"xCLOSED\r\nCLOSEX\r\nCLO"]);
+        let mut buffer = [0; 32];
+        assert_eq!(esp.poll_transparent(&mut buffer, TIMEOUT).unwrap(), Some(TransparentEvent::Data(20)));
+        assert_eq!(&buffer[..20], b"xCLOSED\r\nCLOSEX\r\nCLO");
+    }
+}